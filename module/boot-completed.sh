@@ -0,0 +1,11 @@
+#!/system/bin/sh
+############################################
+# mm-mm boot-completed.sh
+# Tells the bootloop guard this boot got through
+############################################
+
+MODDIR="${0%/*}"
+
+"$MODDIR/meta-mm" boot-completed
+
+exit 0
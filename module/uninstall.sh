@@ -6,6 +6,9 @@
 
 MODDIR="${0%/*}"
 
+# undo this boot's mounts before the state goes away
+"$MODDIR/meta-mm" unmount
+
 rm -rf /data/adb/magic_mount
 
 exit 0
@@ -0,0 +1,327 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+
+use crate::{
+    config::{self, Config},
+    defs::{BOOT_COUNT_FILE, TMPFS_CANDIDATES},
+    init_logger, magic_mount, scanner, utils,
+};
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().skip(2).any(|it| it == flag)
+}
+
+/// Prints `items` as a JSON array with `--json`, one per line otherwise.
+fn print_list<T>(args: &[String], items: &[T]) -> Result<()>
+where
+    T: Serialize + Display,
+{
+    if has_flag(args, "--json") {
+        let json = serde_json::to_string(items)?;
+        println!("{json}");
+    } else {
+        for item in items {
+            println!("{item}");
+        }
+    }
+    Ok(())
+}
+
+fn sync(config: &Config, args: &[String]) -> Result<()> {
+    init_logger(config.verbose);
+    let json = has_flag(args, "--json");
+    magic_mount::configure_xattrs(&config.xattrs, config.keep_caps);
+    // one line per subtree, so a caller can follow along
+    magic_mount::sync(
+        &config.sysroot,
+        &config.moduledir,
+        &config.mountsource,
+        &config.partitions,
+        &config.priority,
+        config.umount && config.sysroot == Path::new("/"),
+        |step| {
+            if json {
+                if let Ok(line) = serde_json::to_string(step) {
+                    println!("{line}");
+                }
+            } else {
+                println!("{step}");
+            }
+        },
+    )
+}
+
+fn quarantine(config: &Config, args: &[String]) -> Result<()> {
+    match args.get(2).map(String::as_str) {
+        Some("list") => print_list(args, &magic_mount::quarantined(&config.moduledir)?),
+        Some("clear") => {
+            let ids: Vec<_> = args
+                .iter()
+                .skip(3)
+                .filter(|it| !it.starts_with("--"))
+                .cloned()
+                .collect();
+            for id in magic_mount::clear_quarantine(&config.moduledir, &ids)? {
+                println!("released {id}");
+            }
+            Ok(())
+        }
+        _ => bail!("usage: quarantine list [--json] | quarantine clear [id...]"),
+    }
+}
+
+fn known_good(config: &Config, args: &[String]) -> Result<()> {
+    match args.get(2).map(String::as_str) {
+        None | Some("show" | "--json") => {
+            let known_good = magic_mount::KnownGood::load()?;
+            if has_flag(args, "--json") {
+                let json = serde_json::to_string(&known_good)?;
+                println!("{json}");
+            } else {
+                println!("{known_good}");
+                let changed = known_good.changed(&config.moduledir);
+                if !changed.is_empty() {
+                    println!("changed since: {}", changed.join(", "));
+                }
+            }
+        }
+        Some("promote") => {
+            let known_good = magic_mount::KnownGood::record(&config.moduledir)?;
+            known_good.save()?;
+            println!("{known_good}");
+        }
+        Some("reset") => magic_mount::KnownGood::reset()?,
+        _ => bail!("usage: known-good [show] [--json] | known-good promote | known-good reset"),
+    }
+    Ok(())
+}
+
+fn check_config(args: &[String]) -> Result<()> {
+    let path = Config::path();
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let issues = config::check(&content);
+
+    if has_flag(args, "--json") {
+        let json = serde_json::to_string(&issues)?;
+        println!("{json}");
+    } else {
+        for issue in &issues {
+            // like a compiler, `file:line:column: message`
+            let separator = if issue.line.is_some() { ":" } else { ": " };
+            println!("{}{separator}{issue}", path.display());
+        }
+    }
+    if !issues.is_empty() {
+        bail!("{} problems in {}", issues.len(), path.display());
+    }
+    Ok(())
+}
+
+fn migrate_config() -> Result<()> {
+    let path = Config::path();
+    let mut document = config::Document::load(&path)?;
+    let changes = config::migrate(&mut document)?;
+
+    if changes.is_empty() {
+        println!("already at version {}", config::CONFIG_VERSION);
+        return Ok(());
+    }
+    document.save(&path)?;
+    for change in changes {
+        println!("{change}");
+    }
+    Ok(())
+}
+
+/// Applies `edit` to the config file and writes it back in one rename. The
+/// file is migrated on the way, so it is current afterwards.
+fn edit_config<F>(edit: F) -> Result<()>
+where
+    F: FnOnce(&mut config::Document) -> Result<()>,
+{
+    let path = Config::path();
+    let mut document = config::Document::load(&path)?;
+    for change in config::migrate(&mut document)? {
+        println!("{change}");
+    }
+    edit(&mut document).with_context(|| format!("refusing to change {}", path.display()))?;
+    document.save(&path)
+}
+
+/// The arguments after the `config` subcommand, without flags.
+fn config_args(args: &[String]) -> Vec<&str> {
+    args.iter()
+        .skip(3)
+        .map(String::as_str)
+        .filter(|it| !it.starts_with("--"))
+        .collect()
+}
+
+/// Runs the `config` subcommands, which do not need a loadable config.
+/// Returns `false` for any other command.
+pub fn config(args: &[String]) -> Result<bool> {
+    if args.get(1).map(String::as_str) != Some("config") {
+        return Ok(false);
+    }
+
+    match args.get(2).map(String::as_str) {
+        Some("check") => check_config(args)?,
+        Some("migrate") => migrate_config()?,
+        Some("get") => {
+            let [key] = config_args(args)[..] else {
+                bail!("usage: config get <key>");
+            };
+            // strings bare for shells, anything else as JSON
+            match config::get(&Config::load()?, key)? {
+                serde_json::Value::Null => {}
+                serde_json::Value::String(value) => println!("{value}"),
+                value => println!("{value}"),
+            }
+        }
+        Some("set") => {
+            let args = config_args(args);
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                bail!("usage: config set <key> <value> [<key> <value>...]");
+            }
+            let pairs: Vec<_> = args.chunks(2).map(|it| (it[0], it[1])).collect();
+            edit_config(|document| config::set(document, &pairs))?;
+        }
+        Some("unset") => {
+            let keys = config_args(args);
+            if keys.is_empty() {
+                bail!("usage: config unset <key>...");
+            }
+            edit_config(|document| config::unset(document, &keys))?;
+        }
+        Some("dump") => {
+            let config = Config::load()?;
+            if has_flag(args, "--json") {
+                let json = serde_json::to_string(&config)?;
+                println!("{json}");
+            } else {
+                print!("{}", toml::to_string(&config)?);
+            }
+        }
+        _ => bail!(
+            "usage: config check [--json] | config migrate | config get <key> \
+             | config set <key> <value>... | config unset <key>... | config dump [--json]"
+        ),
+    }
+    Ok(true)
+}
+
+/// Runs the subcommand named by `args[1]`, returns `false` if there is none
+/// and the modules should be mounted.
+pub fn run(config: &Config, args: &[String]) -> Result<bool> {
+    let Some(command) = args.get(1) else {
+        return Ok(false);
+    };
+
+    match command.as_str() {
+        "scan" => {
+            let modules = scanner::scan_modules(
+                &config.sysroot,
+                &config.moduledir,
+                &config.partitions,
+                &config.priority,
+            );
+
+            if has_flag(args, "--json") {
+                let json = serde_json::to_string(&modules)?;
+                println!("{json}");
+            } else {
+                for module in modules {
+                    println!("{}", module.id);
+                }
+            }
+        }
+        "plan" => {
+            // the temp dir is busy after boot, so only use it to name the work dirs
+            let tempdir = config.tmpfsdir.clone().map_or_else(
+                || utils::select_temp_dir().unwrap_or_else(|_| PathBuf::from(TMPFS_CANDIDATES[0])),
+                PathBuf::from,
+            );
+            let ops = magic_mount::plan(
+                &tempdir,
+                &config.sysroot,
+                &config.moduledir,
+                &config.partitions,
+                &config.priority,
+            )?;
+            print_list(args, &ops)?;
+        }
+        "conflicts" => {
+            let conflicts = magic_mount::conflicts(
+                &config.sysroot,
+                &config.moduledir,
+                &config.partitions,
+                &config.priority,
+            )?;
+            print_list(args, &conflicts)?;
+        }
+        "status" => {
+            let state = magic_mount::MountState::load()?;
+
+            if has_flag(args, "--json") {
+                let json = serde_json::to_string(&state)?;
+                println!("{json}");
+            } else {
+                print!("{state}");
+            }
+        }
+        "unmount" => {
+            init_logger(config.verbose);
+            magic_mount::unmount()?;
+        }
+        "sync" => sync(config, args)?,
+        "quarantine" => quarantine(config, args)?,
+        "boot-completed" => {
+            magic_mount::BootGuard::new(
+                magic_mount::state_file(BOOT_COUNT_FILE),
+                config.bootloop_threshold,
+            )
+            .completed()?;
+            // a boot without mounts has nothing to vouch for
+            match magic_mount::KnownGood::record(&config.moduledir) {
+                Ok(known_good) => known_good.save()?,
+                Err(e) => log::debug!("not recording known good modules: {e:#}"),
+            }
+        }
+        "known-good" => known_good(config, args)?,
+        "tree" => {
+            let root = magic_mount::tree()?;
+
+            if has_flag(args, "--json") {
+                let json = serde_json::to_string(&root)?;
+                println!("{json}");
+            } else {
+                print!("{}", magic_mount::Tree(&root));
+            }
+        }
+        "module-tree" => {
+            let tree = magic_mount::module_tree(
+                &config.sysroot,
+                &config.moduledir,
+                &config.partitions,
+                &config.priority,
+            )?;
+            print!("{tree}");
+        }
+        "version" => {
+            println!("{{ \"version\": \"{}\" }}", env!("CARGO_PKG_VERSION"));
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
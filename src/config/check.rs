@@ -0,0 +1,222 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashSet, fmt, ops::Range, path::Path};
+
+use serde::Serialize;
+use toml::{
+    Spanned,
+    de::{DeTable, DeValue},
+};
+
+use crate::config::{
+    Config,
+    migrate::{CONFIG_VERSION, canonical},
+};
+
+/// Every key `Config` reads.
+pub const KEYS: [&str; 13] = [
+    "version",
+    "moduledir",
+    "mountsource",
+    "verbose",
+    "partitions",
+    "tmpfsdir",
+    "priority",
+    "sysroot",
+    "xattrs",
+    "keep_caps",
+    "quarantine_after",
+    "bootloop_threshold",
+    "umount",
+];
+
+/// One problem with a config file, placed where the file allows it.
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    pub key: Option<String>,
+    pub message: String,
+    /// 1-based
+    pub line: Option<usize>,
+    /// 1-based, in chars
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{line}:{column}: ")?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{key}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+struct Checker<'a> {
+    content: &'a str,
+    issues: Vec<Issue>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, key: Option<&str>, span: Option<Range<usize>>, message: String) {
+        let position = span.map(|span| {
+            let before = &self.content[..span.start.min(self.content.len())];
+            let line_start = before.rfind('\n').map_or(0, |it| it + 1);
+            (
+                before.matches('\n').count() + 1,
+                before[line_start..].chars().count() + 1,
+            )
+        });
+        self.issues.push(Issue {
+            key: key.map(str::to_string),
+            message,
+            line: position.map(|it| it.0),
+            column: position.map(|it| it.1),
+        });
+    }
+
+    fn string<'v>(&mut self, key: &str, value: &'v Spanned<DeValue<'_>>) -> Option<&'v str> {
+        if let DeValue::String(it) = value.get_ref() {
+            Some(it)
+        } else {
+            self.report(
+                Some(key),
+                Some(value.span()),
+                "expected a string".to_string(),
+            );
+            None
+        }
+    }
+
+    fn version(&mut self, value: &Spanned<DeValue<'_>>) {
+        let problem = match value.get_ref() {
+            DeValue::Integer(it) => match it.as_str().parse::<u32>() {
+                Ok(version) if version > CONFIG_VERSION => Some(format!(
+                    "{version} is newer than the supported {CONFIG_VERSION}"
+                )),
+                Ok(_) => None,
+                Err(_) => Some("expected a schema version".to_string()),
+            },
+            _ => Some("expected a schema version".to_string()),
+        };
+        if let Some(problem) = problem {
+            self.report(Some("version"), Some(value.span()), problem);
+        }
+    }
+
+    fn mountsource(&mut self, value: &Spanned<DeValue<'_>>) {
+        if self.string("mountsource", value).is_some_and(str::is_empty) {
+            self.report(
+                Some("mountsource"),
+                Some(value.span()),
+                "must not be empty".to_string(),
+            );
+        }
+    }
+
+    fn tmpfsdir(&mut self, value: &Spanned<DeValue<'_>>) {
+        let Some(dir) = self.string("tmpfsdir", value) else {
+            return;
+        };
+        let path = Path::new(dir);
+        let problem = if dir.is_empty() {
+            Some("must not be empty, leave it out to pick one".to_string())
+        } else if !path.is_dir() {
+            Some(format!("{dir} is not a directory"))
+        } else if path.read_dir().is_ok_and(|mut it| it.next().is_some()) {
+            Some(format!("{dir} is not empty"))
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            self.report(Some("tmpfsdir"), Some(value.span()), problem);
+        }
+    }
+
+    fn partitions(&mut self, value: &Spanned<DeValue<'_>>) {
+        let DeValue::Array(partitions) = value.get_ref() else {
+            self.report(
+                Some("partitions"),
+                Some(value.span()),
+                "expected an array of partition names".to_string(),
+            );
+            return;
+        };
+
+        let mut seen = HashSet::new();
+        for partition in partitions {
+            let Some(name) = self.string("partitions", partition) else {
+                continue;
+            };
+            let problem = if name.is_empty() || name == "." || name == ".." {
+                Some(format!("{name:?} is not a partition name"))
+            } else if name.contains('/') {
+                Some(format!("{name:?} must be a name, not a path"))
+            } else if name == "system" {
+                Some("system is always mounted, leave it out".to_string())
+            } else if !seen.insert(name) {
+                Some(format!("{name:?} is listed twice"))
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                self.report(Some("partitions"), Some(partition.span()), problem);
+            }
+        }
+    }
+
+    fn table(&mut self, table: &DeTable<'_>) {
+        // old names are fine, `config migrate` renames them
+        if !table
+            .keys()
+            .any(|it| canonical(it.get_ref()) == "moduledir")
+        {
+            self.report(
+                Some("moduledir"),
+                None,
+                "is missing, the module dir has to be set".to_string(),
+            );
+        }
+
+        for (key, value) in table {
+            match canonical(key.get_ref()) {
+                "version" => self.version(value),
+                "mountsource" => self.mountsource(value),
+                "tmpfsdir" => self.tmpfsdir(value),
+                "partitions" => self.partitions(value),
+                name if KEYS.contains(&name) => {}
+                name => self.report(Some(name), Some(key.span()), "unknown key".to_string()),
+            }
+        }
+    }
+}
+
+/// Every problem in the config `content`, an empty list means it is safe to
+/// save.
+pub fn check(content: &str) -> Vec<Issue> {
+    let mut checker = Checker {
+        content,
+        issues: Vec::new(),
+    };
+
+    match DeTable::parse(content) {
+        Ok(table) => checker.table(table.get_ref()),
+        Err(e) => {
+            checker.report(None, e.span(), e.message().to_string());
+            return checker.issues;
+        }
+    }
+
+    // the types and required keys, as serde sees them
+    if let Err(e) = toml::from_str::<Config>(content) {
+        // a missing key comes with an empty span at the start
+        let span = e.span().filter(|it| *it != (0..0));
+        checker.report(None, span, e.message().to_string());
+    }
+
+    // in file order, the ones without a place first
+    checker.issues.sort_by_key(|it| (it.line, it.column));
+    checker.issues
+}
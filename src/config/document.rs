@@ -0,0 +1,178 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    ops::Range,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+use toml::de::DeTable;
+
+/// A config file kept as text. Edits splice the spans the parser reports,
+/// so comments, order and layout of everything else survive.
+#[derive(Debug, Clone)]
+pub struct Document {
+    content: String,
+}
+
+impl Document {
+    pub fn parse(content: &str) -> Result<Self> {
+        DeTable::parse(content).map_err(|e| anyhow!("{e}"))?;
+        Ok(Self {
+            content: content.to_string(),
+        })
+    }
+
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// The spans of the top level `key` and its value.
+    fn spans(&self, key: &str) -> Option<(Range<usize>, Range<usize>)> {
+        let table = DeTable::parse(&self.content).ok()?;
+        table
+            .get_ref()
+            .iter()
+            .find(|(it, _)| it.get_ref() == key)
+            .map(|(key, value)| (key.span(), value.span()))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.spans(key).is_some()
+    }
+
+    /// The text of the value of `key`, as written.
+    pub fn raw(&self, key: &str) -> Option<&str> {
+        self.spans(key).map(|(_, value)| &self.content[value])
+    }
+
+    fn line_start(&self, at: usize) -> usize {
+        self.content[..at].rfind('\n').map_or(0, |it| it + 1)
+    }
+
+    fn line_end(&self, at: usize) -> usize {
+        self.content[at..]
+            .find('\n')
+            .map_or(self.content.len(), |it| at + it + 1)
+    }
+
+    /// Sets `key` to the TOML value `value`, in place if it is there. A new
+    /// key goes before the first one with `first`, after the last otherwise.
+    pub fn set(&mut self, key: &str, value: &str, first: bool) {
+        if let Some((_, span)) = self.spans(key) {
+            self.content.replace_range(span, value);
+            return;
+        }
+
+        let line = format!("{key} = {value}\n");
+        let table = DeTable::parse(&self.content).ok();
+        let spans = table
+            .iter()
+            .flat_map(|it| it.get_ref().iter())
+            .map(|(key, value)| (key.span().start, value.span().end));
+        let at = if first {
+            spans.map(|it| it.0).min().map(|it| self.line_start(it))
+        } else {
+            spans.map(|it| it.1).max().map(|it| self.line_end(it))
+        };
+
+        if let Some(at) = at {
+            self.content.insert_str(at, &line);
+        } else {
+            // no keys yet, only comments if anything
+            if !self.content.is_empty() && !self.content.ends_with('\n') {
+                self.content.push('\n');
+            }
+            self.content.push_str(&line);
+        }
+    }
+
+    /// Renames the top level `from` to `to`, keeping its value.
+    pub fn rename(&mut self, from: &str, to: &str) {
+        if let Some((span, _)) = self.spans(from) {
+            self.content.replace_range(span, to);
+        }
+    }
+
+    /// Drops the line of the top level `key`.
+    pub fn remove(&mut self, key: &str) {
+        if let Some((key, value)) = self.spans(key) {
+            let range = self.line_start(key.start)..self.line_end(value.end);
+            self.content.replace_range(range, "");
+        }
+    }
+
+    /// Writes the file through a temporary one next to it, so a reader never
+    /// sees half of it, and a power cut leaves the old or the new one.
+    pub fn save<P>(&self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} is not a file", path.display()))?;
+        // two writers never share a temporary file
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |it| it.subsec_nanos());
+        let temp = dir.join(format!(
+            ".{}.{}.{nanos}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+
+        let ret = self.write_temp(&temp, path, dir).and_then(|()| {
+            fs::rename(&temp, path).with_context(|| format!("failed to replace {}", path.display()))
+        });
+        if ret.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        ret?;
+        sync_dir(dir)
+    }
+
+    /// Writes and syncs `temp`, with the mode of `path` if there is one.
+    fn write_temp(&self, temp: &Path, path: &Path, dir: &Path) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(temp)
+            .with_context(|| format!("failed to create {}", temp.display()))?;
+        file.write_all(self.content.as_bytes())
+            .with_context(|| format!("failed to write {}", temp.display()))?;
+        // the replacement keeps the mode of the file it replaces
+        if let Ok(metadata) = fs::metadata(path) {
+            let _ = file.set_permissions(metadata.permissions());
+        }
+        file.sync_all()
+            .with_context(|| format!("failed to sync {}", temp.display()))?;
+        // the temporary file has to be there before it can replace anything
+        sync_dir(dir)
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|it| it.sync_all())
+        .with_context(|| format!("failed to sync {}", dir.display()))
+}
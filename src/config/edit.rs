@@ -0,0 +1,88 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Result, bail};
+
+use crate::config::{
+    Config,
+    check::{KEYS, check},
+    document::Document,
+    migrate::canonical,
+};
+
+/// The key `name` stands for, if `Config` reads it.
+fn key(name: &str) -> Result<&'static str> {
+    let key = canonical(name);
+    match KEYS.iter().find(|it| **it == key) {
+        Some(&"version") => bail!("version is kept by `config migrate`"),
+        Some(key) => Ok(key),
+        None => bail!("unknown key {name}"),
+    }
+}
+
+/// A value as given on the command line, TOML if it parses as one and a
+/// string otherwise, so paths and names need no quotes.
+fn value(raw: &str) -> String {
+    raw.parse::<toml::Value>()
+        .unwrap_or_else(|_| toml::Value::String(raw.to_string()))
+        .to_string()
+}
+
+/// Applies `edit` to `document`, unless that adds problems `check` finds.
+/// The ones the file already had do not count.
+fn validated<F>(document: &mut Document, edit: F) -> Result<()>
+where
+    F: FnOnce(&mut Document) -> Result<()>,
+{
+    let known: Vec<_> = check(document.content())
+        .into_iter()
+        .map(|it| (it.key, it.message))
+        .collect();
+
+    let mut edited = document.clone();
+    edit(&mut edited)?;
+    let problems: Vec<_> = check(edited.content())
+        .into_iter()
+        .map(|it| (it.key, it.message))
+        .filter(|it| !known.contains(it))
+        .map(|(key, message)| {
+            key.map_or_else(|| message.clone(), |key| format!("{key}: {message}"))
+        })
+        .collect();
+    if !problems.is_empty() {
+        bail!("{}", problems.join(", "));
+    }
+
+    *document = edited;
+    Ok(())
+}
+
+/// The value of `name` in `config`, defaults included. Unset is null.
+pub fn get(config: &Config, name: &str) -> Result<serde_json::Value> {
+    let key = canonical(name);
+    if !KEYS.contains(&key) {
+        bail!("unknown key {name}");
+    }
+    let mut json = serde_json::to_value(config)?;
+    Ok(json[key].take())
+}
+
+/// Sets every key in `pairs` to its value, all of them or none.
+pub fn set(document: &mut Document, pairs: &[(&str, &str)]) -> Result<()> {
+    validated(document, |document| {
+        for (name, raw) in pairs {
+            document.set(key(name)?, &value(raw), false);
+        }
+        Ok(())
+    })
+}
+
+/// Drops every key in `names`, so their defaults apply.
+pub fn unset(document: &mut Document, names: &[&str]) -> Result<()> {
+    validated(document, |document| {
+        for name in names {
+            document.remove(key(name)?);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,61 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Result, bail};
+
+use crate::config::document::Document;
+
+/// The schema this binary reads and writes. Files without a version are 0.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Other names keys have gone by, in old releases or other tools.
+pub const ALIASES: [(&str, &str); 1] = [
+    // written by the WebUI
+    ("tempdir", "tmpfsdir"),
+];
+
+/// The current name of `key`.
+pub fn canonical(key: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == key)
+        .map_or(key, |(_, it)| it)
+}
+
+/// The schema version `document` declares.
+pub fn version(document: &Document) -> Result<u32> {
+    document.raw("version").map_or(Ok(0), |raw| {
+        raw.parse()
+            .map_err(|_| anyhow::anyhow!("version must be a number, not {raw}"))
+    })
+}
+
+/// Brings `document` to `CONFIG_VERSION` and returns what changed, nothing
+/// if it already was.
+pub fn migrate(document: &mut Document) -> Result<Vec<String>> {
+    let version = version(document)?;
+    if version > CONFIG_VERSION {
+        bail!("config version {version} is newer than the supported {CONFIG_VERSION}");
+    }
+
+    let mut changes = Vec::new();
+    for (alias, key) in ALIASES {
+        if !document.contains(alias) {
+            continue;
+        }
+        if document.contains(key) {
+            document.remove(alias);
+            changes.push(format!("dropped {alias}, {key} is set"));
+        } else {
+            document.rename(alias, key);
+            changes.push(format!("renamed {alias} to {key}"));
+        }
+    }
+
+    if version < CONFIG_VERSION {
+        document.set("version", &CONFIG_VERSION.to_string(), true);
+        changes.push(format!("version {version} -> {CONFIG_VERSION}"));
+    }
+
+    Ok(changes)
+}
@@ -0,0 +1,136 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+mod check;
+mod document;
+mod edit;
+mod migrate;
+#[cfg(test)]
+mod tests;
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub use crate::config::{
+    check::check,
+    document::Document,
+    edit::{get, set, unset},
+    migrate::{CONFIG_VERSION, migrate},
+};
+use crate::defs::{CONFIG_ENV, CONFIG_FILE};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Config {
+    /// schema of the file, see `migrate`
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default = "default_moduledir")]
+    pub moduledir: PathBuf,
+    #[serde(default = "default_mountsource")]
+    pub mountsource: String,
+    pub verbose: bool,
+    pub partitions: Vec<String>,
+    pub tmpfsdir: Option<String>,
+    /// module ids that win conflicts, highest priority first
+    #[serde(default)]
+    pub priority: Vec<String>,
+    /// the root the modules are mounted into
+    #[serde(default = "default_sysroot")]
+    pub sysroot: PathBuf,
+    /// xattrs kept on recreated nodes besides the selinux context, a trailing
+    /// `*` matches any suffix
+    #[serde(default)]
+    pub xattrs: Vec<String>,
+    /// give module files the capabilities of the stock file they replace
+    #[serde(default)]
+    pub keep_caps: bool,
+    /// failed runs in a row before a module is quarantined, 0 never does
+    #[serde(default = "default_quarantine_after")]
+    pub quarantine_after: u32,
+    /// boots in a row without boot-completed before the modules are skipped,
+    /// 0 never skips
+    #[serde(default = "default_bootloop_threshold")]
+    pub bootloop_threshold: u32,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub umount: bool,
+}
+
+fn default_moduledir() -> PathBuf {
+    PathBuf::from("/data/adb/modules/")
+}
+
+fn default_sysroot() -> PathBuf {
+    PathBuf::from("/")
+}
+
+const fn default_quarantine_after() -> u32 {
+    3
+}
+
+const fn default_bootloop_threshold() -> u32 {
+    3
+}
+
+fn default_mountsource() -> String {
+    String::from("KSU")
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "module real path: {}", self.moduledir.display())?;
+        writeln!(f, "mount source: {}", self.mountsource)?;
+        if self.verbose {
+            writeln!(f, "u enable debug mode!!")?;
+        }
+        if self.sysroot != Path::new("/") {
+            writeln!(f, "sysroot: {}", self.sysroot.display())?;
+        }
+        if !self.xattrs.is_empty() {
+            writeln!(f, "kept xattrs: {:?}", self.xattrs)?;
+        }
+        if self.keep_caps {
+            writeln!(f, "keeping file capabilities")?;
+        }
+        if self.bootloop_threshold == 0 {
+            writeln!(f, "bootloop guard disabled")?;
+        }
+        if self.quarantine_after == 0 {
+            writeln!(f, "quarantine disabled")?;
+        }
+        if !self.priority.is_empty() {
+            writeln!(f, "module priority: {:?}", self.priority)?;
+        }
+        if self.partitions.is_empty() {
+            write!(f, "no extra partitions")
+        } else {
+            write!(f, "extra partitions is {:?}", self.partitions)
+        }
+    }
+}
+
+impl Config {
+    /// `CONFIG_FILE`, unless `CONFIG_ENV` points somewhere else.
+    pub fn path() -> PathBuf {
+        std::env::var_os(CONFIG_ENV).map_or_else(|| PathBuf::from(CONFIG_FILE), PathBuf::from)
+    }
+
+    /// Loads the config, brought to `CONFIG_VERSION` in memory. Only
+    /// `config migrate` writes that back.
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        let mut document = Document::load(&path)?;
+        for change in migrate(&mut document)? {
+            log::warn!("outdated config, run `meta-mm config migrate`: {change}");
+        }
+
+        let config: Self = toml::from_str(document.content())
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{fs, os::unix::fs::PermissionsExt};
+
+use crate::config::{
+    CONFIG_VERSION, Config, Document,
+    check::{KEYS, check},
+    get, migrate, set, unset,
+};
+
+const VALID: &str = r#"moduledir = "/data/adb/modules/"
+mountsource = "KSU"
+verbose = false
+umount = false
+partitions = ["mi_ext"]
+"#;
+
+#[test]
+fn check_knows_every_config_key() {
+    let mut config: Config = toml::from_str(VALID).unwrap();
+    config.tmpfsdir = Some("/debug_ramdisk".to_string());
+    let table: toml::Table = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+
+    let mut keys: Vec<_> = table.keys().map(String::as_str).collect();
+    let mut known = KEYS.to_vec();
+    keys.sort_unstable();
+    known.sort_unstable();
+    assert_eq!(keys, known);
+    assert!(check(VALID).is_empty());
+}
+
+#[test]
+fn check_places_every_problem() {
+    let tmpfsdir = std::env::temp_dir().join(format!("mmrs-check-{}", std::process::id()));
+    fs::create_dir_all(&tmpfsdir).unwrap();
+    fs::write(tmpfsdir.join("leftover"), "").unwrap();
+
+    let content = format!(
+        "mountsource = \"\"\nverbose = false\numount = false\ntmpfsdir = {tmpfsdir:?}\n\
+         partitions = [\"mi_ext\", \"system\", \"vendor/etc\", \"mi_ext\"]\nmodule_dir = \"typo\"\n"
+    );
+    let issues = check(&content);
+    let _ = fs::remove_dir_all(&tmpfsdir);
+
+    let found: Vec<_> = issues
+        .iter()
+        .map(|it| (it.key.as_deref(), it.line, it.column))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (Some("moduledir"), None, None),
+            (Some("mountsource"), Some(1), Some(15)),
+            (Some("tmpfsdir"), Some(4), Some(12)),
+            (Some("partitions"), Some(5), Some(25)),
+            (Some("partitions"), Some(5), Some(35)),
+            (Some("partitions"), Some(5), Some(49)),
+            (Some("module_dir"), Some(6), Some(1)),
+        ]
+    );
+    assert!(issues[6].message.contains("unknown"));
+
+    // no newline after it, so the parser stops on the line of the array
+    let broken = check("verbose = false\npartitions = [");
+    assert_eq!(broken.len(), 1);
+    assert_eq!((broken[0].line, broken[0].column), (Some(2), Some(15)));
+}
+
+#[test]
+fn migrate_renames_aliases_and_keeps_the_layout() {
+    // what the WebUI writes
+    let old = "# Magic Mount Configuration File\n\n\
+               moduledir = \"/data/adb/modules/\"\n\
+               tempdir = \"/debug_ramdisk\" # picked by hand\n\
+               mountsource = \"KSU\"\nverbose = false\numount = true\npartitions = []";
+    let mut document = Document::parse(old).unwrap();
+
+    let changes = migrate(&mut document).unwrap();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(
+        document.content(),
+        format!(
+            "# Magic Mount Configuration File\n\n\
+             version = {CONFIG_VERSION}\n\
+             moduledir = \"/data/adb/modules/\"\n\
+             tmpfsdir = \"/debug_ramdisk\" # picked by hand\n\
+             mountsource = \"KSU\"\nverbose = false\numount = true\npartitions = []"
+        )
+    );
+    let config: Config = toml::from_str(document.content()).unwrap();
+    assert_eq!(config.tmpfsdir.as_deref(), Some("/debug_ramdisk"));
+
+    // a second run has nothing left to do
+    assert!(migrate(&mut document).unwrap().is_empty());
+}
+
+#[test]
+fn migrate_prefers_the_current_key_and_refuses_newer_files() {
+    let mut both =
+        Document::parse("version = 1\ntempdir = \"/old\"\ntmpfsdir = \"/new\"\nverbose = false\n")
+            .unwrap();
+    migrate(&mut both).unwrap();
+    assert_eq!(
+        both.content(),
+        "version = 1\ntmpfsdir = \"/new\"\nverbose = false\n"
+    );
+
+    let mut newer = Document::parse("version = 99\n").unwrap();
+    assert!(migrate(&mut newer).is_err());
+    assert!(
+        check("version = 99\n")
+            .iter()
+            .any(|it| it.key.as_deref() == Some("version") && it.line == Some(1))
+    );
+}
+
+#[test]
+fn set_edits_in_place_and_refuses_bad_values() {
+    let content = format!("# kept\n{VALID}");
+    let mut document = Document::parse(&content).unwrap();
+
+    set(
+        &mut document,
+        &[
+            ("verbose", "true"),
+            ("mountsource", "APatch"),
+            ("priority", r#"["a"]"#),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        document.content(),
+        content
+            .replace("verbose = false", "verbose = true")
+            .replace("\"KSU\"", "\"APatch\"")
+            + "priority = [\"a\"]\n"
+    );
+    let config: Config = toml::from_str(document.content()).unwrap();
+    assert_eq!(get(&config, "priority").unwrap(), serde_json::json!(["a"]));
+    assert_eq!(get(&config, "keep_caps").unwrap(), serde_json::json!(false));
+
+    // nothing changes when any of it is wrong
+    let before = document.content().to_string();
+    assert!(
+        set(
+            &mut document,
+            &[("verbose", "false"), ("partitions", r#"["system"]"#)]
+        )
+        .is_err()
+    );
+    assert!(set(&mut document, &[("verbose", "yes")]).is_err());
+    assert!(set(&mut document, &[("nope", "1")]).is_err());
+    assert!(unset(&mut document, &["verbose"]).is_err());
+    assert_eq!(document.content(), before);
+
+    unset(&mut document, &["priority"]).unwrap();
+    assert_eq!(
+        document.content(),
+        content
+            .replace("verbose = false", "verbose = true")
+            .replace("\"KSU\"", "\"APatch\"")
+    );
+}
+
+#[test]
+fn save_replaces_the_file_and_leaves_no_temporary_one() {
+    let dir = std::env::temp_dir().join(format!("mmrs-save-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    fs::write(&path, "verbose = true\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+    Document::parse(VALID).unwrap().save(&path).unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), VALID);
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|it| it.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["config.toml"]);
+    let _ = fs::remove_dir_all(&dir);
+}
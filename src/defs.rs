@@ -3,13 +3,44 @@
 
 // utils
 pub const SELINUX_XATTR: &str = "security.selinux";
+pub const CAPABILITY_XATTR: &str = "security.capability";
+// only present while selinuxfs is mounted
+pub const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
 pub const TMPFS_CANDIDATES: &[&str] = &["/debug_ramdisk", "/patch_hw", "/oem", "/root", "/sbin"];
 // magic_mount
 pub const DISABLE_FILE_NAME: &str = "disable";
 pub const REMOVE_FILE_NAME: &str = "remove";
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
-pub const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
+// written by us when a module keeps breaking the mount
+pub const QUARANTINE_FILE_NAME: &str = "quarantine";
+// overlayfs markers, user.* is what unprivileged overlay mounts use
+pub const REPLACE_DIR_XATTRS: &[&str] = &["trusted.overlay.opaque", "user.overlay.opaque"];
+pub const WHITEOUT_XATTRS: &[&str] = &["trusted.overlay.whiteout", "user.overlay.whiteout"];
 pub const REPLACE_DIR_FILE_NAME: &str = ".replace";
+pub const FILE_CONTEXTS_FILE_NAME: &str = "file_contexts";
+// aufs markers, `.wh.<name>` hides <name>
+pub const AUFS_WHITEOUT_PREFIX: &str = ".wh.";
+pub const AUFS_META_PREFIX: &str = ".wh..wh.";
+pub const AUFS_OPAQUE_FILE_NAME: &str = ".wh..wh..opq";
 
 // config
 pub const CONFIG_FILE: &str = "/data/adb/magic_mount/config.toml";
+// overrides CONFIG_FILE, for running against a fixture
+pub const CONFIG_ENV: &str = "MAGIC_MOUNT_CONFIG";
+
+// state, in STATE_DIR below the sysroot so a run against another root keeps
+// its own
+pub const STATE_DIR: &str = "data/adb/magic_mount";
+pub const STATE_FILE: &str = "state.json";
+// per module rules, `<id>.toml`, kept with the state
+pub const RULES_DIR: &str = "rules";
+pub const TREE_FILE: &str = "tree";
+// next to the mm.log written by metamount.sh
+pub const REPORT_FILE: &str = "report.json";
+// failed runs per module, until it is quarantined
+pub const STRIKES_FILE: &str = "strikes.json";
+// boots that never signalled boot-completed
+pub const BOOT_COUNT_FILE: &str = "boot_count";
+// modules of the last boot that completed
+pub const KNOWN_GOOD_FILE: &str = "known_good.json";
+pub const BOOT_ID_FILE: &str = "/proc/sys/kernel/random/boot_id";
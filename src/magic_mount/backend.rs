@@ -0,0 +1,349 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cell::RefCell,
+    fs::{self, create_dir, create_dir_all},
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use extattr::{Flags as XattrFlags, lgetxattr, llistxattr, lsetxattr};
+use rustix::{
+    fs::{CWD, FileType, Gid, Mode, Uid, chmod, chown, mknodat},
+    mount::{
+        MountFlags, MountPropagationFlags, UnmountFlags, mount, mount_bind, mount_change,
+        mount_move, mount_remount, unmount,
+    },
+};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::utils::ksucalls::try_umount::send_unmountable;
+use crate::{
+    magic_mount::journal::check_terminated,
+    utils::{lgetfilecon, lsetfilecon},
+};
+
+/// Every side effect the mount engine has on the system.
+pub trait MountBackend {
+    fn mount_tmpfs(&self, source: &str, target: &Path) -> Result<()>;
+    fn mount_bind(&self, source: &Path, target: &Path) -> Result<()>;
+    fn mount_move(&self, source: &Path, target: &Path) -> Result<()>;
+    /// `MS_REMOUNT | MS_BIND | MS_RDONLY`
+    fn remount_ro(&self, target: &Path) -> Result<()>;
+    fn make_private(&self, target: &Path) -> Result<()>;
+    fn unmount(&self, target: &Path) -> Result<()>;
+
+    fn create_dir(&self, path: &Path) -> Result<()>;
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+    fn remove_dir(&self, path: &Path) -> Result<()>;
+    fn create_file(&self, path: &Path) -> Result<()>;
+    /// contents and mode, nothing else
+    fn copy_file(&self, src: &Path, dst: &Path) -> Result<()>;
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()>;
+    /// `mode` carries the file type, `dev` the device number if any
+    fn mknod(&self, path: &Path, mode: u32, dev: u64) -> Result<()>;
+    fn chmod(&self, path: &Path, mode: u32) -> Result<()>;
+    fn chown(&self, path: &Path, uid: u32, gid: u32) -> Result<()>;
+    fn get_filecon(&self, path: &Path) -> Result<String>;
+    fn set_filecon(&self, path: &Path, con: &str) -> Result<()>;
+    fn list_xattrs(&self, path: &Path) -> Result<Vec<String>>;
+    fn get_xattr(&self, path: &Path, name: &str) -> Result<Vec<u8>>;
+    fn set_xattr(&self, path: &Path, name: &str, value: &[u8]) -> Result<()>;
+
+    /// Hands a mount point over to the ksu `try_umount` list.
+    fn send_unmountable(&self, target: &Path);
+}
+
+fn list_xattrs(path: &Path) -> Result<Vec<String>> {
+    Ok(llistxattr(path)?
+        .into_iter()
+        .map(|it| it.to_string_lossy().to_string())
+        .collect())
+}
+
+/// Applies everything to the running system.
+pub struct RealBackend {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    umount: bool,
+}
+
+impl RealBackend {
+    pub const fn new(
+        #[cfg(any(target_os = "linux", target_os = "android"))] umount: bool,
+        #[cfg(not(any(target_os = "linux", target_os = "android")))] _umount: bool,
+    ) -> Self {
+        Self {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            umount,
+        }
+    }
+}
+
+impl MountBackend for RealBackend {
+    fn mount_tmpfs(&self, source: &str, target: &Path) -> Result<()> {
+        mount(source, target, "tmpfs", MountFlags::empty(), None)?;
+        Ok(())
+    }
+
+    fn mount_bind(&self, source: &Path, target: &Path) -> Result<()> {
+        mount_bind(source, target)?;
+        Ok(())
+    }
+
+    fn mount_move(&self, source: &Path, target: &Path) -> Result<()> {
+        mount_move(source, target)?;
+        Ok(())
+    }
+
+    fn remount_ro(&self, target: &Path) -> Result<()> {
+        // we should use MS_REMOUNT | MS_BIND | MS_xxx to change mount flags
+        mount_remount(target, MountFlags::RDONLY | MountFlags::BIND, "")?;
+        Ok(())
+    }
+
+    fn make_private(&self, target: &Path) -> Result<()> {
+        mount_change(target, MountPropagationFlags::PRIVATE)?;
+        Ok(())
+    }
+
+    fn unmount(&self, target: &Path) -> Result<()> {
+        unmount(target, UnmountFlags::DETACH)?;
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        create_dir(path)?;
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        fs::remove_dir(path)?;
+        Ok(())
+    }
+
+    fn create_file(&self, path: &Path) -> Result<()> {
+        fs::File::create(path)?;
+        Ok(())
+    }
+
+    fn copy_file(&self, src: &Path, dst: &Path) -> Result<()> {
+        fs::copy(src, dst)?;
+        Ok(())
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        symlink(original, link)?;
+        Ok(())
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, dev: u64) -> Result<()> {
+        mknodat(
+            CWD,
+            path,
+            FileType::from_raw_mode(mode),
+            Mode::from_raw_mode(mode),
+            dev,
+        )?;
+        Ok(())
+    }
+
+    fn chmod(&self, path: &Path, mode: u32) -> Result<()> {
+        chmod(path, Mode::from_raw_mode(mode))?;
+        Ok(())
+    }
+
+    fn chown(&self, path: &Path, uid: u32, gid: u32) -> Result<()> {
+        chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+        Ok(())
+    }
+
+    fn get_filecon(&self, path: &Path) -> Result<String> {
+        lgetfilecon(path)
+    }
+
+    fn set_filecon(&self, path: &Path, con: &str) -> Result<()> {
+        lsetfilecon(path, con)
+    }
+
+    fn list_xattrs(&self, path: &Path) -> Result<Vec<String>> {
+        list_xattrs(path)
+    }
+
+    fn get_xattr(&self, path: &Path, name: &str) -> Result<Vec<u8>> {
+        Ok(lgetxattr(path, name)?)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &str, value: &[u8]) -> Result<()> {
+        lsetxattr(path, name, value, XattrFlags::empty())?;
+        Ok(())
+    }
+
+    fn send_unmountable(&self, target: &Path) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.umount {
+            let _ = send_unmountable(target);
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let _ = target;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Tmpfs(PathBuf),
+    Bind(PathBuf, PathBuf),
+    Move(PathBuf, PathBuf),
+    RemountRo(PathBuf),
+    Private(PathBuf),
+    Unmount(PathBuf),
+    CreateDir(PathBuf),
+    RemoveDir(PathBuf),
+    CreateFile(PathBuf),
+    CopyFile(PathBuf, PathBuf),
+    Symlink(PathBuf, PathBuf),
+    Mknod(PathBuf, u32, u64),
+    Chmod(PathBuf, u32),
+    Chown(PathBuf, u32, u32),
+    SetFilecon(PathBuf, String),
+    SetXattr(PathBuf, String, Vec<u8>),
+    Unmountable(PathBuf),
+}
+
+/// Records every call instead of applying it. Reads still go to the real
+/// filesystem, so `plan` sees the real tree and tests a fixture.
+#[derive(Default)]
+pub struct RecordingBackend {
+    pub calls: RefCell<Vec<Call>>,
+    /// binds from this source fail, to test error paths
+    pub fail_bind: Option<PathBuf>,
+    /// binds from this source raise SIGTERM and fail like the `Journal`
+    /// does, to test shutdowns
+    pub sigterm_bind: Option<PathBuf>,
+}
+
+impl RecordingBackend {
+    fn record(&self, call: Call) {
+        self.calls.borrow_mut().push(call);
+    }
+}
+
+impl MountBackend for RecordingBackend {
+    fn mount_tmpfs(&self, _source: &str, target: &Path) -> Result<()> {
+        self.record(Call::Tmpfs(target.into()));
+        Ok(())
+    }
+
+    fn mount_bind(&self, source: &Path, target: &Path) -> Result<()> {
+        self.record(Call::Bind(source.into(), target.into()));
+        if self.fail_bind.as_deref() == Some(source) {
+            anyhow::bail!("bind {} failed", source.display());
+        }
+        if self.sigterm_bind.as_deref() == Some(source) {
+            unsafe { libc::raise(libc::SIGTERM) };
+            check_terminated()?;
+        }
+        Ok(())
+    }
+
+    fn mount_move(&self, source: &Path, target: &Path) -> Result<()> {
+        self.record(Call::Move(source.into(), target.into()));
+        Ok(())
+    }
+
+    fn remount_ro(&self, target: &Path) -> Result<()> {
+        self.record(Call::RemountRo(target.into()));
+        Ok(())
+    }
+
+    fn make_private(&self, target: &Path) -> Result<()> {
+        self.record(Call::Private(target.into()));
+        Ok(())
+    }
+
+    fn unmount(&self, target: &Path) -> Result<()> {
+        self.record(Call::Unmount(target.into()));
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        self.record(Call::CreateDir(path.into()));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.record(Call::CreateDir(path.into()));
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        self.record(Call::RemoveDir(path.into()));
+        Ok(())
+    }
+
+    fn create_file(&self, path: &Path) -> Result<()> {
+        self.record(Call::CreateFile(path.into()));
+        Ok(())
+    }
+
+    fn copy_file(&self, src: &Path, dst: &Path) -> Result<()> {
+        self.record(Call::CopyFile(src.into(), dst.into()));
+        Ok(())
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        self.record(Call::Symlink(original.into(), link.into()));
+        Ok(())
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, dev: u64) -> Result<()> {
+        self.record(Call::Mknod(path.into(), mode, dev));
+        Ok(())
+    }
+
+    fn chmod(&self, path: &Path, mode: u32) -> Result<()> {
+        self.record(Call::Chmod(path.into(), mode));
+        Ok(())
+    }
+
+    fn chown(&self, path: &Path, uid: u32, gid: u32) -> Result<()> {
+        self.record(Call::Chown(path.into(), uid, gid));
+        Ok(())
+    }
+
+    fn get_filecon(&self, _path: &Path) -> Result<String> {
+        Ok("u:object_r:system_file:s0".to_string())
+    }
+
+    fn set_filecon(&self, path: &Path, con: &str) -> Result<()> {
+        self.record(Call::SetFilecon(path.into(), con.to_string()));
+        Ok(())
+    }
+
+    fn list_xattrs(&self, path: &Path) -> Result<Vec<String>> {
+        list_xattrs(path)
+    }
+
+    fn get_xattr(&self, path: &Path, name: &str) -> Result<Vec<u8>> {
+        Ok(lgetxattr(path, name)?)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &str, value: &[u8]) -> Result<()> {
+        self.record(Call::SetXattr(
+            path.into(),
+            name.to_string(),
+            value.to_vec(),
+        ));
+        Ok(())
+    }
+
+    fn send_unmountable(&self, target: &Path) {
+        self.record(Call::Unmountable(target.into()));
+    }
+}
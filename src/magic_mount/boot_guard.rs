@@ -0,0 +1,83 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// What to mount in this boot.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootVerdict {
+    All,
+    /// only the last known good modules, and why
+    KnownGood(String),
+    /// nothing, and why
+    Skip(String),
+}
+
+/// Counts the boots that mounted modules but never reached boot-completed,
+/// in the file `counter`. `enter` runs before mounting, `completed` stands
+/// for the boot-completed signal.
+#[derive(Debug)]
+pub struct BootGuard {
+    counter: PathBuf,
+    /// failed boots before the mounts are skipped, 0 never skips
+    threshold: u32,
+}
+
+impl BootGuard {
+    pub fn new<P>(counter: P, threshold: u32) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            counter: counter.as_ref().to_path_buf(),
+            threshold,
+        }
+    }
+
+    /// Boots in a row that did not complete, a missing counter means none.
+    pub fn count(&self) -> u32 {
+        fs::read_to_string(&self.counter)
+            .ok()
+            .and_then(|it| it.trim().parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Counts this boot and decides what it mounts. After `threshold` failed
+    /// boots in a row it falls back to the `known_good` modules if there are
+    /// any, after as many more it skips everything.
+    pub fn enter(&self, known_good: bool) -> BootVerdict {
+        let count = self.count();
+        // an unwritable counter must not keep the modules from mounting
+        if let Err(e) = fs::write(&self.counter, (count + 1).to_string()) {
+            log::warn!("failed to write {}: {e}", self.counter.display());
+        }
+
+        if self.threshold == 0 || count < self.threshold {
+            BootVerdict::All
+        } else if known_good && count < self.threshold * 2 {
+            BootVerdict::KnownGood(format!(
+                "{count} boots in a row did not complete, mounting the last known good modules"
+            ))
+        } else {
+            BootVerdict::Skip(format!(
+                "{count} boots in a row did not complete, skipping all modules"
+            ))
+        }
+    }
+
+    /// The boot got through, so the modules are mounted again next time.
+    pub fn completed(&self) -> Result<()> {
+        match fs::remove_file(&self.counter) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("failed to remove {}", self.counter.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+
+/// A path shipped by more than one module, only `winner` gets mounted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub winner: String,
+    /// every module shipping `path`, from the lowest priority to the highest
+    pub modules: Vec<String>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({} wins)",
+            self.path.display(),
+            self.modules.join(", "),
+            self.winner
+        )
+    }
+}
+
+/// Collects conflicts while walking the module dirs.
+#[derive(Debug)]
+pub struct Conflicts {
+    module_dir: PathBuf,
+    list: Vec<Conflict>,
+}
+
+impl Conflicts {
+    pub fn new<P>(module_dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            module_dir: module_dir.as_ref().to_path_buf(),
+            list: Vec::new(),
+        }
+    }
+
+    /// Splits `<module_dir>/<id>/<partition>/...` into the module id and the
+    /// path it targets.
+    fn resolve(&self, module_path: &Path) -> Option<(String, PathBuf)> {
+        let mut components = module_path
+            .strip_prefix(&self.module_dir)
+            .ok()?
+            .components();
+        let Some(Component::Normal(id)) = components.next() else {
+            return None;
+        };
+        Some((
+            id.to_string_lossy().to_string(),
+            Path::new("/").join(components.as_path()),
+        ))
+    }
+
+    /// `winner` overrides `loser`, which was collected before it.
+    pub fn record(&mut self, winner: &Path, loser: &Path) {
+        let (Some((winner, path)), Some((loser, _))) = (self.resolve(winner), self.resolve(loser))
+        else {
+            return;
+        };
+
+        let conflict = if let Some(index) = self.list.iter().position(|it| it.path == path) {
+            &mut self.list[index]
+        } else {
+            self.list.push(Conflict {
+                path,
+                winner: winner.clone(),
+                modules: Vec::new(),
+            });
+            self.list.last_mut().unwrap()
+        };
+
+        for id in [loser, winner.clone()] {
+            if !conflict.modules.contains(&id) {
+                conflict.modules.push(id);
+            }
+        }
+        conflict.winner = winner;
+    }
+
+    pub fn into_vec(self) -> Vec<Conflict> {
+        self.list
+    }
+}
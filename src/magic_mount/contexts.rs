@@ -0,0 +1,144 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use regex_lite::Regex;
+
+use crate::{
+    defs::FILE_CONTEXTS_FILE_NAME,
+    magic_mount::{node::NodeFileType, utils::device_path},
+};
+
+#[derive(Debug)]
+struct Spec {
+    regex: Regex,
+    file_type: Option<NodeFileType>,
+    context: String,
+}
+
+/// Labels for the files of one module, read from `<module>/file_contexts`
+/// in the format of the system one:
+///
+/// ```text
+/// /system/bin/foo              u:object_r:system_file:s0
+/// /vendor/etc/foo(/.*)?        u:object_r:vendor_configs_file:s0
+/// /system/etc/bar          -d  u:object_r:system_file:s0
+/// ```
+///
+/// Every regex has to match the whole device path, the last matching line
+/// wins. Paths nobody matches keep the label they have in the module dir.
+#[derive(Debug, Default)]
+pub struct FileContexts {
+    specs: Vec<Spec>,
+    /// the module dir the paths are resolved against
+    module: PathBuf,
+}
+
+fn parse_file_type(field: &str) -> Result<NodeFileType> {
+    Ok(match field {
+        "--" => NodeFileType::RegularFile,
+        "-d" => NodeFileType::Directory,
+        "-l" => NodeFileType::Symlink,
+        "-p" => NodeFileType::Fifo,
+        "-s" => NodeFileType::Socket,
+        "-c" => NodeFileType::CharDevice,
+        "-b" => NodeFileType::BlockDevice,
+        _ => bail!("unknown file type {field}"),
+    })
+}
+
+fn parse_spec(line: &str) -> Result<Spec> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let (pattern, file_type, context) = match fields[..] {
+        [pattern, context] => (pattern, None, context),
+        [pattern, file_type, context] => (pattern, Some(parse_file_type(file_type)?), context),
+        _ => bail!("expected `<regex> [type] <context>`"),
+    };
+    // user:role:type:level, the level may have colons of its own
+    if context.split(':').count() < 4 {
+        bail!("malformed context {context}");
+    }
+
+    Ok(Spec {
+        regex: Regex::new(&format!("^(?:{pattern})$"))
+            .with_context(|| format!("invalid regex {pattern}"))?,
+        file_type,
+        context: context.to_string(),
+    })
+}
+
+impl FileContexts {
+    /// Loads the contexts of the module at `module`, a missing file means none.
+    /// Broken lines are reported and left out.
+    pub fn load<P>(module: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let module = module.as_ref();
+        let file = module.join(FILE_CONTEXTS_FILE_NAME);
+        let mut contexts = Self {
+            module: module.to_path_buf(),
+            ..Self::default()
+        };
+        if !file.exists() {
+            return Ok(contexts);
+        }
+
+        let content = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read {}", file.display()))?;
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_spec(line) {
+                Ok(spec) => contexts.specs.push(spec),
+                Err(e) => log::error!("{}:{}: {e:#}", file.display(), index + 1),
+            }
+        }
+
+        Ok(contexts)
+    }
+
+    /// Like `load`, but an unreadable file only costs a warning.
+    pub fn load_or_default<P>(module: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self::load(&module).unwrap_or_else(|e| {
+            log::warn!("ignoring file contexts: {e:#}");
+            Self {
+                module: module.as_ref().to_path_buf(),
+                ..Self::default()
+            }
+        })
+    }
+
+    /// Maps `<module>/system/...` to the path it ends up at on the device.
+    fn target(&self, module_path: &Path) -> Option<PathBuf> {
+        module_path.strip_prefix(&self.module).ok().map(device_path)
+    }
+
+    /// The context for the module entry at `module_path`, if any line matches.
+    pub fn lookup(&self, module_path: &Path, file_type: &NodeFileType) -> Option<String> {
+        if self.specs.is_empty() {
+            return None;
+        }
+        let target = self.target(module_path)?;
+        let target = target.to_string_lossy();
+
+        self.specs
+            .iter()
+            .rev()
+            .find(|spec| {
+                spec.file_type.as_ref().is_none_or(|it| it == file_type)
+                    && spec.regex.is_match(&target)
+            })
+            .map(|spec| spec.context.clone())
+    }
+}
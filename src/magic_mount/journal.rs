@@ -0,0 +1,187 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Result, bail};
+
+use crate::magic_mount::backend::MountBackend;
+
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigterm(_: libc::c_int) {
+    TERMINATED.store(true, Ordering::Relaxed);
+}
+
+/// Turns SIGTERM into an error at the next mount operation, so an interrupted
+/// run is rolled back instead of dying half way.
+pub fn catch_sigterm() {
+    unsafe {
+        libc::signal(
+            libc::SIGTERM,
+            handle_sigterm as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+/// Forgets a SIGTERM, so tests can go on after one.
+#[cfg(test)]
+pub fn reset_terminated() {
+    TERMINATED.store(false, Ordering::Relaxed);
+}
+
+pub fn check_terminated() -> Result<()> {
+    if TERMINATED.load(Ordering::Relaxed) {
+        bail!("interrupted by SIGTERM");
+    }
+    Ok(())
+}
+
+/// Wraps a backend and remembers every mount it makes on the real tree, so
+/// they can be undone if the run fails. Mounts inside `scratch` are skipped,
+/// they go away together with it.
+pub struct Journal<'a, B>
+where
+    B: MountBackend,
+{
+    backend: &'a B,
+    scratch: PathBuf,
+    mounts: RefCell<Vec<PathBuf>>,
+}
+
+impl<'a, B> Journal<'a, B>
+where
+    B: MountBackend,
+{
+    pub fn new<P>(backend: &'a B, scratch: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            backend,
+            scratch: scratch.as_ref().to_path_buf(),
+            mounts: RefCell::default(),
+        }
+    }
+
+    fn record(&self, target: &Path) {
+        if !target.starts_with(&self.scratch) {
+            self.mounts.borrow_mut().push(target.to_path_buf());
+        }
+    }
+
+    /// Unmounts everything recorded, newest first.
+    pub fn rollback(&self) {
+        let mounts = self.mounts.take();
+        log::warn!("rolling back {} mounts", mounts.len());
+
+        for target in mounts.iter().rev() {
+            match self.backend.unmount(target) {
+                Ok(()) => log::info!("rolled back {}", target.display()),
+                Err(e) => log::error!("failed to roll back {}: {e:#}", target.display()),
+            }
+        }
+    }
+}
+
+impl<B> MountBackend for Journal<'_, B>
+where
+    B: MountBackend,
+{
+    fn mount_tmpfs(&self, source: &str, target: &Path) -> Result<()> {
+        check_terminated()?;
+        self.backend.mount_tmpfs(source, target)?;
+        self.record(target);
+        Ok(())
+    }
+
+    fn mount_bind(&self, source: &Path, target: &Path) -> Result<()> {
+        check_terminated()?;
+        self.backend.mount_bind(source, target)?;
+        self.record(target);
+        Ok(())
+    }
+
+    fn mount_move(&self, source: &Path, target: &Path) -> Result<()> {
+        check_terminated()?;
+        self.backend.mount_move(source, target)?;
+        self.record(target);
+        Ok(())
+    }
+
+    fn remount_ro(&self, target: &Path) -> Result<()> {
+        self.backend.remount_ro(target)
+    }
+
+    fn make_private(&self, target: &Path) -> Result<()> {
+        self.backend.make_private(target)
+    }
+
+    fn unmount(&self, target: &Path) -> Result<()> {
+        self.backend.unmount(target)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        self.backend.create_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.backend.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        self.backend.remove_dir(path)
+    }
+
+    fn create_file(&self, path: &Path) -> Result<()> {
+        self.backend.create_file(path)
+    }
+
+    fn copy_file(&self, src: &Path, dst: &Path) -> Result<()> {
+        self.backend.copy_file(src, dst)
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        self.backend.symlink(original, link)
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, dev: u64) -> Result<()> {
+        self.backend.mknod(path, mode, dev)
+    }
+
+    fn chmod(&self, path: &Path, mode: u32) -> Result<()> {
+        self.backend.chmod(path, mode)
+    }
+
+    fn chown(&self, path: &Path, uid: u32, gid: u32) -> Result<()> {
+        self.backend.chown(path, uid, gid)
+    }
+
+    fn get_filecon(&self, path: &Path) -> Result<String> {
+        self.backend.get_filecon(path)
+    }
+
+    fn set_filecon(&self, path: &Path, con: &str) -> Result<()> {
+        self.backend.set_filecon(path, con)
+    }
+
+    fn list_xattrs(&self, path: &Path) -> Result<Vec<String>> {
+        self.backend.list_xattrs(path)
+    }
+
+    fn get_xattr(&self, path: &Path, name: &str) -> Result<Vec<u8>> {
+        self.backend.get_xattr(path, name)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &str, value: &[u8]) -> Result<()> {
+        self.backend.set_xattr(path, name, value)
+    }
+
+    fn send_unmountable(&self, target: &Path) {
+        self.backend.send_unmountable(target);
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::BTreeMap, fmt, fs, io::ErrorKind, path::Path, slice, sync::OnceLock};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs::KNOWN_GOOD_FILE,
+    magic_mount::{
+        node::Node,
+        state::{MountState, state_file, write_state_file},
+        tree,
+    },
+};
+
+static ONLY: OnceLock<Vec<String>> = OnceLock::new();
+
+/// The modules of the last boot that completed, saved to `KNOWN_GOOD_FILE`.
+/// After failed boots only these are mounted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownGood {
+    /// unix time it was recorded
+    pub time: u64,
+    pub modules: Vec<String>,
+    /// of the ids and module.prop of `modules`, see `fingerprint`
+    pub fingerprint: String,
+    /// `fingerprint` of each module on its own, by id
+    #[serde(default)]
+    pub fingerprints: BTreeMap<String, String>,
+    /// the tree that was mounted
+    pub tree: Option<Node>,
+}
+
+impl fmt::Display for KnownGood {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "recorded at: {}", self.time)?;
+        writeln!(f, "fingerprint: {}", self.fingerprint)?;
+        write!(f, "modules: {}", self.modules.join(", "))
+    }
+}
+
+/// FNV-1a of every id in `modules` with its module.prop, so an updated
+/// module changes it too.
+pub fn fingerprint(module_dir: &Path, modules: &[String]) -> String {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let mut modules = modules.to_vec();
+    modules.sort();
+    let mut hash = OFFSET;
+    for id in &modules {
+        let prop = fs::read(module_dir.join(id).join("module.prop")).unwrap_or_default();
+        for byte in id.bytes().chain([0]).chain(prop).chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+impl KnownGood {
+    /// Takes the modules mounted in this boot as known good.
+    pub fn record(module_dir: &Path) -> Result<Self> {
+        let state = MountState::load()?;
+        if state.stale || !state.success {
+            bail!("no successful mount in this boot to take as known good");
+        }
+
+        Ok(Self {
+            time: state.time,
+            fingerprint: fingerprint(module_dir, &state.modules),
+            fingerprints: state
+                .modules
+                .iter()
+                .map(|id| (id.clone(), fingerprint(module_dir, slice::from_ref(id))))
+                .collect(),
+            modules: state.modules,
+            tree: tree().ok(),
+        })
+    }
+
+    /// The modules that are no longer what they were when recorded. A
+    /// snapshot without per module fingerprints can only tell all or none.
+    pub fn changed(&self, module_dir: &Path) -> Vec<String> {
+        if self.fingerprints.is_empty() {
+            return if fingerprint(module_dir, &self.modules) == self.fingerprint {
+                Vec::new()
+            } else {
+                self.modules.clone()
+            };
+        }
+
+        self.modules
+            .iter()
+            .filter(|id| {
+                self.fingerprints
+                    .get(*id)
+                    .is_none_or(|it| *it != fingerprint(module_dir, slice::from_ref(id)))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_state_file(KNOWN_GOOD_FILE, &serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load() -> Result<Self> {
+        let file = state_file(KNOWN_GOOD_FILE);
+        let json = fs::read_to_string(&file).with_context(|| {
+            format!("failed to read {}, no boot completed yet?", file.display())
+        })?;
+        serde_json::from_str(&json).with_context(|| format!("failed to parse {}", file.display()))
+    }
+
+    /// Forgets the snapshot, until the next boot completes.
+    pub fn reset() -> Result<()> {
+        let file = state_file(KNOWN_GOOD_FILE);
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("failed to remove {}", file.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Limits this run to the modules in `ids`, before anything is collected.
+pub fn restrict_modules(ids: &[String]) {
+    let _ = ONLY.set(ids.to_vec());
+}
+
+/// Whether the module `id` may be mounted in this run.
+pub fn allowed(id: &str) -> bool {
+    ONLY.get().is_none_or(|ids| ids.iter().any(|it| it == id))
+}
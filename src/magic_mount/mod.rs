@@ -1,50 +1,145 @@
 // Copyright 2025 Magic Mount-rs Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+mod backend;
+mod boot_guard;
+mod conflict;
+mod contexts;
+mod journal;
+mod known_good;
+mod module_tree;
 mod node;
+mod plan;
+mod quarantine;
+mod report;
+mod rules;
+mod state;
+mod sync;
+#[cfg(test)]
+mod tests;
 mod utils;
+mod xattr;
 
 use std::{
-    fs,
+    fmt, fs,
     path::{Path, PathBuf},
-    sync::atomic::AtomicU32,
 };
 
 use anyhow::{Context, Result, bail};
-use rustix::mount::{
-    MountFlags, MountPropagationFlags, UnmountFlags, mount, mount_bind, mount_change, mount_move,
-    mount_remount, unmount,
-};
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
-use crate::utils::ksucalls::try_umount::{LIST, send_unmountable};
-use crate::{
-    magic_mount::{
-        node::{Node, NodeFileType},
-        utils::{clone_symlink, collect_module_files, mount_mirror},
-    },
-    utils::ensure_dir_exists,
+use crate::defs::TREE_FILE;
+use crate::magic_mount::{
+    backend::{MountBackend, RealBackend},
+    journal::{Journal, catch_sigterm, check_terminated},
+    module_tree::render_module_tree,
+    node::NodeFileType,
+    plan::Operation,
+    report::{Event, Recorder},
+    state::{MountKind, module_id, write_state_file},
+    sync::node_at,
+    utils::{apply_context, clone_device, clone_symlink, collect_module_files, mount_mirror},
+    xattr::{carry_caps, policy},
 };
+pub use crate::magic_mount::{
+    boot_guard::{BootGuard, BootVerdict},
+    conflict::Conflict,
+    known_good::{KnownGood, restrict_modules},
+    module_tree::module_tree,
+    node::{Node, Tree},
+    quarantine::{Quarantine, clear_quarantine, load_quarantine, quarantined, update_quarantine},
+    report::MountReport,
+    rules::ModuleRules,
+    state::{MountState, configure_state_dir, state_file},
+    sync::sync,
+    xattr::configure_xattrs,
+};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::utils::ksucalls::try_umount::LIST;
 
-static MOUNTDED_FILES: AtomicU32 = AtomicU32::new(0);
-static MOUNTDED_SYMBOLS_FILES: AtomicU32 = AtomicU32::new(0);
+/// Decides whether the directory `node` at `path` has to be rebuilt as a tmpfs.
+/// Children that would need one under a root dir are marked as skipped.
+fn need_tmpfs(path: &Path, node: &mut Node, has_tmpfs: bool) -> bool {
+    let tmpfs = !has_tmpfs && node.replace && node.module_path.is_some();
 
-struct MagicMount {
+    if has_tmpfs || tmpfs {
+        return tmpfs;
+    }
+
+    for (name, child) in &mut node.children {
+        let real_path = path.join(name);
+        let need = match child.file_type {
+            NodeFileType::Symlink | NodeFileType::CharDevice | NodeFileType::BlockDevice => true,
+            NodeFileType::Whiteout => real_path.exists(),
+            _ => {
+                if let Ok(metadata) = real_path.symlink_metadata() {
+                    let file_type = NodeFileType::from(metadata.file_type());
+                    file_type != node.file_type || file_type == NodeFileType::Symlink
+                } else {
+                    // real path not exists
+                    true
+                }
+            }
+        };
+        if need {
+            if node.module_path.is_none() {
+                log::error!("cannot create tmpfs on {}, ignore: {name}", path.display());
+                child.skip = true;
+                continue;
+            }
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Context naming the innermost module entry a mount error came from.
+#[derive(Debug)]
+struct FailedEntry(PathBuf);
+
+impl fmt::Display for FailedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "module entry {}", self.0.display())
+    }
+}
+
+/// The module whose entry a failed run broke on. A shutdown is no module's
+/// fault.
+fn blamed_module(module_dir: &Path, error: &anyhow::Error) -> Option<String> {
+    if check_terminated().is_err() {
+        return None;
+    }
+    error
+        .downcast_ref::<FailedEntry>()
+        .and_then(|it| module_id(module_dir, &it.0))
+}
+
+struct MagicMount<'a, B>
+where
+    B: MountBackend,
+{
     node: Node,
     path: PathBuf,
     work_dir_path: PathBuf,
     has_tmpfs: bool,
-    #[cfg(any(target_os = "linux", target_os = "android"))]
-    umount: bool,
+    backend: &'a B,
+    recorder: &'a Recorder,
+    /// module dir to look up the module behind a failed child, none
+    /// disables recovery
+    recovery: Option<PathBuf>,
 }
 
-impl MagicMount {
+impl<'a, B> MagicMount<'a, B>
+where
+    B: MountBackend,
+{
     fn new<P>(
         node: &Node,
         path: P,
         work_dir_path: P,
         has_tmpfs: bool,
-        #[cfg(any(target_os = "linux", target_os = "android"))] umount: bool,
+        backend: &'a B,
+        recorder: &'a Recorder,
     ) -> Self
     where
         P: AsRef<Path>,
@@ -54,25 +149,73 @@ impl MagicMount {
             path: path.as_ref().join(node.name.clone()),
             work_dir_path: work_dir_path.as_ref().join(node.name.clone()),
             has_tmpfs,
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            umount,
+            backend,
+            recorder,
+            recovery: None,
         }
     }
 
+    /// Retries a failed child without the module that broke it.
+    fn with_recovery<P>(mut self, module_dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.recovery = Some(module_dir.as_ref().to_path_buf());
+        self
+    }
+
+    fn child<P>(&self, node: &Node, work_dir_path: P, has_tmpfs: bool) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let mut child = Self::new(
+            node,
+            self.path.as_path(),
+            work_dir_path.as_ref(),
+            has_tmpfs,
+            self.backend,
+            self.recorder,
+        );
+        child.recovery.clone_from(&self.recovery);
+        child
+    }
+
     fn do_mount(&mut self) -> Result<()> {
+        let result = self.mount_node();
+        match (&self.node.module_path, result) {
+            (Some(module_path), Err(e)) if e.downcast_ref::<FailedEntry>().is_none() => {
+                Err(e.context(FailedEntry(module_path.clone())))
+            }
+            (_, result) => result,
+        }
+    }
+
+    fn mount_node(&mut self) -> Result<()> {
         match self.node.file_type {
             NodeFileType::Symlink => self.symlink(),
-            NodeFileType::RegularFile => self.regular_file(),
+            // a bind mount works for any inode that is not a dir
+            NodeFileType::RegularFile | NodeFileType::Fifo | NodeFileType::Socket => {
+                self.regular_file()
+            }
+            NodeFileType::CharDevice | NodeFileType::BlockDevice => self.device(),
             NodeFileType::Directory => self.directory(),
             NodeFileType::Whiteout => {
                 log::debug!("file {} is removed", self.path.display());
+                self.recorder.record(
+                    Event::Whiteout,
+                    &self.path,
+                    self.node.module_path.as_deref(),
+                );
                 Ok(())
             }
         }
     }
 }
 
-impl MagicMount {
+impl<B> MagicMount<'_, B>
+where
+    B: MountBackend,
+{
     fn symlink(&self) -> Result<()> {
         if let Some(module_path) = &self.node.module_path {
             log::debug!(
@@ -80,24 +223,60 @@ impl MagicMount {
                 module_path.display(),
                 self.work_dir_path.display()
             );
-            clone_symlink(module_path, &self.work_dir_path).with_context(|| {
+            clone_symlink(self.backend, module_path, &self.work_dir_path).with_context(|| {
                 format!(
                     "create module symlink {} -> {}",
                     module_path.display(),
                     self.work_dir_path.display(),
                 )
             })?;
-            let mounted = MOUNTDED_SYMBOLS_FILES.load(std::sync::atomic::Ordering::Relaxed) + 1;
-            MOUNTDED_SYMBOLS_FILES.store(mounted, std::sync::atomic::Ordering::Relaxed);
+            apply_context(self.backend, &self.node, &self.work_dir_path);
+            self.recorder
+                .record_mount(&self.path, MountKind::Symlink, Some(module_path));
+            self.recorder
+                .record(Event::Symlink, &self.path, Some(module_path));
             Ok(())
         } else {
             bail!("cannot mount root symlink {}!", self.path.display());
         }
     }
 
+    /// Device nodes are recreated with mknod, a bind mount from a nodev mount
+    /// like /data would leave them unusable. So they always need a tmpfs.
+    fn device(&self) -> Result<()> {
+        let Some(module_path) = &self.node.module_path else {
+            bail!("cannot mount root device {}!", self.path.display());
+        };
+        if !self.has_tmpfs {
+            bail!(
+                "device {} can only be created in a tmpfs",
+                self.path.display()
+            );
+        }
+
+        log::debug!(
+            "create module device {} -> {}",
+            module_path.display(),
+            self.work_dir_path.display()
+        );
+        clone_device(self.backend, module_path, &self.work_dir_path).with_context(|| {
+            format!(
+                "create module device {} -> {}",
+                module_path.display(),
+                self.work_dir_path.display(),
+            )
+        })?;
+        apply_context(self.backend, &self.node, &self.work_dir_path);
+        self.recorder
+            .record_mount(&self.path, MountKind::Device, Some(module_path));
+        self.recorder
+            .record(Event::Device, &self.path, Some(module_path));
+        Ok(())
+    }
+
     fn regular_file(&self) -> Result<()> {
         let target = if self.has_tmpfs {
-            fs::File::create(&self.work_dir_path)?;
+            self.backend.create_file(&self.work_dir_path)?;
             &self.work_dir_path
         } else {
             &self.path
@@ -108,85 +287,68 @@ impl MagicMount {
         }
 
         let module_path = &self.node.module_path.clone().unwrap();
+        apply_context(self.backend, &self.node, module_path);
+        let source = if self.path.is_file() {
+            carry_caps(
+                self.backend,
+                policy(),
+                &self.path,
+                module_path,
+                &self.work_dir_path,
+            )
+        } else {
+            module_path.clone()
+        };
 
-        log::debug!(
-            "mount module file {} -> {}",
-            module_path.display(),
-            self.work_dir_path.display()
-        );
+        // a clone in the tmpfs is in place already
+        if source != *target {
+            log::debug!(
+                "mount module file {} -> {}",
+                source.display(),
+                self.work_dir_path.display()
+            );
 
-        mount_bind(module_path, target).with_context(|| {
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            if self.umount {
+            self.backend.mount_bind(&source, target).with_context(|| {
                 // tell ksu about this mount
-                let _ = send_unmountable(target);
-            }
-            format!(
-                "mount module file {} -> {}",
-                module_path.display(),
-                self.work_dir_path.display(),
-            )
-        })?;
+                self.backend.send_unmountable(target);
+                format!(
+                    "mount module file {} -> {}",
+                    source.display(),
+                    self.work_dir_path.display(),
+                )
+            })?;
 
-        // we should use MS_REMOUNT | MS_BIND | MS_xxx to change mount flags
-        if let Err(e) = mount_remount(target, MountFlags::RDONLY | MountFlags::BIND, "") {
-            log::warn!("make file {} ro: {e:#?}", target.display());
+            if let Err(e) = self.backend.remount_ro(target) {
+                log::warn!("make file {} ro: {e:#?}", target.display());
+            }
         }
 
-        let mounted = MOUNTDED_FILES.load(std::sync::atomic::Ordering::Relaxed) + 1;
-        MOUNTDED_FILES.store(mounted, std::sync::atomic::Ordering::Relaxed);
+        self.recorder
+            .record_mount(&self.path, MountKind::File, Some(module_path));
+        self.recorder
+            .record(Event::Bind, &self.path, Some(module_path));
         Ok(())
     }
 
     #[allow(clippy::too_many_lines)]
     fn directory(&mut self) -> Result<()> {
-        let mut tmpfs = !self.has_tmpfs && self.node.replace && self.node.module_path.is_some();
-
-        if !self.has_tmpfs && !tmpfs {
-            for it in &mut self.node.children {
-                let (name, node) = it;
-                let real_path = self.path.join(name);
-                let need = match node.file_type {
-                    NodeFileType::Symlink => true,
-                    NodeFileType::Whiteout => real_path.exists(),
-                    _ => {
-                        if let Ok(metadata) = real_path.symlink_metadata() {
-                            let file_type = NodeFileType::from(metadata.file_type());
-                            file_type != self.node.file_type || file_type == NodeFileType::Symlink
-                        } else {
-                            // real path not exists
-                            true
-                        }
-                    }
-                };
-                if need {
-                    if self.node.module_path.is_none() {
-                        log::error!(
-                            "cannot create tmpfs on {}, ignore: {name}",
-                            self.path.display()
-                        );
-                        node.skip = true;
-                        continue;
-                    }
-                    tmpfs = true;
-                    break;
-                }
-            }
-        }
+        let tmpfs = need_tmpfs(&self.path, &mut self.node, self.has_tmpfs);
         let has_tmpfs = tmpfs || self.has_tmpfs;
 
         if has_tmpfs {
-            utils::tmpfs_skeleton(&self.path, &self.work_dir_path, &self.node)?;
+            utils::tmpfs_skeleton(self.backend, &self.path, &self.work_dir_path, &self.node)?;
         }
 
         if tmpfs {
-            mount_bind(&self.work_dir_path, &self.work_dir_path).with_context(|| {
-                format!(
-                    "creating tmpfs for {} at {}",
-                    self.path.display(),
-                    self.work_dir_path.display(),
-                )
-            })?;
+            self.backend
+                .mount_bind(&self.work_dir_path, &self.work_dir_path)
+                .with_context(|| {
+                    format!(
+                        "creating tmpfs for {} at {}",
+                        self.path.display(),
+                        self.work_dir_path.display(),
+                    )
+                })?;
         }
 
         if self.path.exists() && !self.node.replace {
@@ -209,24 +371,15 @@ impl MagicMount {
                 continue;
             }
 
-            if let Err(e) = {
-                Self::new(
-                    node,
-                    &self.path,
-                    &self.work_dir_path,
-                    has_tmpfs,
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    self.umount,
-                )
+            if let Err(e) = self
+                .child(node, &self.work_dir_path, has_tmpfs)
                 .do_mount()
-            }
-            .with_context(|| format!("magic mount {}/{name}", self.path.display()))
+                .with_context(|| format!("magic mount {}/{name}", self.path.display()))
             {
                 if has_tmpfs {
                     return Err(e);
                 }
-
-                log::error!("mount child {}/{name} failed: {e:#?}", self.path.display());
+                self.child_failed(node.clone(), e)?;
             }
         }
 
@@ -237,143 +390,396 @@ impl MagicMount {
                 self.path.display()
             );
 
-            if let Err(e) = mount_remount(
-                &self.work_dir_path,
-                MountFlags::RDONLY | MountFlags::BIND,
-                "",
-            ) {
+            if let Err(e) = self.backend.remount_ro(&self.work_dir_path) {
                 log::warn!("make dir {} ro: {e:#?}", self.path.display());
             }
-            mount_move(&self.work_dir_path, &self.path).with_context(|| {
-                format!(
-                    "moving tmpfs {} -> {}",
-                    self.work_dir_path.display(),
-                    self.path.display()
-                )
-            })?;
+            self.backend
+                .mount_move(&self.work_dir_path, &self.path)
+                .with_context(|| {
+                    format!(
+                        "moving tmpfs {} -> {}",
+                        self.work_dir_path.display(),
+                        self.path.display()
+                    )
+                })?;
             // make private to reduce peer group count
-            if let Err(e) = mount_change(&self.path, MountPropagationFlags::PRIVATE) {
+            if let Err(e) = self.backend.make_private(&self.path) {
                 log::warn!("make dir {} private: {e:#?}", self.path.display());
             }
 
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            if self.umount {
-                // tell ksu about this one too
-                let _ = send_unmountable(&self.path);
-            }
+            // tell ksu about this one too
+            self.backend.send_unmountable(&self.path);
+            self.recorder.record_tmpfs(&self.path);
+            self.recorder
+                .record(Event::Tmpfs, &self.path, self.node.module_path.as_deref());
         }
         Ok(())
     }
 }
 
-impl MagicMount {
+impl<B> MagicMount<'_, B>
+where
+    B: MountBackend,
+{
     fn mount_path(&mut self, has_tmpfs: bool) -> Result<()> {
         for entry in self.path.read_dir()?.flatten() {
             let name = entry.file_name().to_string_lossy().to_string();
-            let result = {
-                if let Some(node) = self.node.children.remove(&name) {
-                    if node.skip {
-                        continue;
-                    }
+            if let Some(node) = self.node.children.remove(&name) {
+                if node.skip {
+                    continue;
+                }
 
-                    Self::new(
-                        &node,
-                        &self.path,
-                        &self.work_dir_path,
-                        has_tmpfs,
-                        #[cfg(any(target_os = "linux", target_os = "android"))]
-                        self.umount,
-                    )
+                if let Err(e) = self
+                    .child(&node, &self.work_dir_path, has_tmpfs)
                     .do_mount()
                     .with_context(|| format!("magic mount {}/{name}", self.path.display()))
-                } else if has_tmpfs {
-                    mount_mirror(&self.path, &self.work_dir_path, &entry)
-                        .with_context(|| format!("mount mirror {}/{name}", self.path.display()))
-                } else {
-                    Ok(())
+                {
+                    if has_tmpfs {
+                        return Err(e);
+                    }
+                    self.child_failed(node, e)?;
                 }
-            };
+            } else if has_tmpfs {
+                mount_mirror(self.backend, &self.path, &self.work_dir_path, &entry)
+                    .with_context(|| format!("mount mirror {}/{name}", self.path.display()))?;
+                self.recorder.record(Event::Mirror, &entry.path(), None);
+            }
+        }
 
-            if let Err(e) = result {
-                if has_tmpfs {
-                    return Err(e);
+        Ok(())
+    }
+
+    /// Handles a child that failed outside a tmpfs, where nothing of it got
+    /// onto the real tree. With recovery on, the module behind the error is
+    /// dropped from the child and the rest of it mounted again, until it
+    /// works or no module is left to blame. A shutdown is no module's fault,
+    /// its error ends the run instead.
+    fn child_failed(&self, mut node: Node, mut error: anyhow::Error) -> Result<()> {
+        let path = self.path.join(&node.name);
+        let mut excluded = Vec::new();
+
+        if let Some(module_dir) = &self.recovery {
+            while check_terminated().is_ok()
+                && let Some(module) = error
+                    .downcast_ref::<FailedEntry>()
+                    .and_then(|it| module_id(module_dir, &it.0))
+                    .filter(|it| !excluded.contains(it))
+            {
+                log::warn!(
+                    "excluding module {module} from {}: {error:#}",
+                    path.display()
+                );
+                self.recorder.record_excluded(&path, &module, &error);
+                // the failed attempt only touched the work dir
+                self.recorder.forget(&path);
+                if !node.drop_module(&module_dir.join(&module)) {
+                    return Ok(());
+                }
+                excluded.push(module);
+
+                let work_dir = self.work_dir_path.join(format!(".retry{}", excluded.len()));
+                match self
+                    .child(&node, &work_dir, false)
+                    .do_mount()
+                    .with_context(|| format!("magic mount {}", path.display()))
+                {
+                    Ok(()) => return Ok(()),
+                    Err(e) => error = e,
                 }
-                log::error!("mount child {}/{name} failed: {e:#?}", self.path.display());
             }
         }
 
+        if check_terminated().is_err() {
+            return Err(error);
+        }
+        log::error!("mount child {} failed: {error:#?}", path.display());
+        self.recorder
+            .record_failure(&path, node.module_path.as_deref(), &error);
         Ok(())
     }
 }
 
+/// Hands the mount points collected by `send_unmountable` over to ksu.
+fn flush_unmountable(umount: bool) -> Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if umount {
+        LIST.lock().unwrap().flags(2);
+        LIST.lock()
+            .unwrap()
+            .format_msg(|p| format!("umount {p:?} successful"));
+        LIST.lock().unwrap().umount()?;
+    }
+    Ok(())
+}
+
+/// Marks the children of root dirs that cannot be mounted, before anything is
+/// mounted, so the saved tree shows them.
+fn mark_skipped(path: &Path, node: &mut Node) {
+    if node.module_path.is_some() {
+        return;
+    }
+    need_tmpfs(path, node, false);
+    for (name, child) in &mut node.children {
+        mark_skipped(&path.join(name), child);
+    }
+}
+
+/// Writes the collected tree to `TREE_FILE`, with the dirs that became a tmpfs.
+fn save_tree(root: &mut Node, sysroot: &Path, tmpfs: &[PathBuf]) -> Result<()> {
+    fn mark(node: &mut Node, path: &Path, tmpfs: &[PathBuf]) {
+        node.tmpfs = tmpfs.iter().any(|it| it == path);
+        for (name, child) in &mut node.children {
+            mark(child, &path.join(name), tmpfs);
+        }
+    }
+
+    mark(root, sysroot, tmpfs);
+    write_state_file(TREE_FILE, &serde_json::to_string_pretty(root)?)
+}
+
+fn save_state(
+    tempdir: &Path,
+    sysroot: &Path,
+    module_dir: &Path,
+    root: &mut Node,
+    recorder: &Recorder,
+    result: &Result<()>,
+) {
+    let state = MountState::collect(tempdir, sysroot, module_dir, root, recorder, result);
+    if let Err(e) = state.save() {
+        log::warn!("failed to save mount state: {e:#}");
+    }
+    if let Err(e) = save_tree(root, sysroot, &state.tmpfs) {
+        log::warn!("failed to save node tree: {e:#}");
+    }
+}
+
+/// Takes what did not get mounted off the tree, so the saved state and tree
+/// match the real one: the modules recovery left out of a subtree, and the
+/// children that failed, which stay in the tree as skipped.
+fn prune(root: &mut Node, sysroot: &Path, module_dir: &Path, report: &MountReport) {
+    fn split<'a>(sysroot: &Path, path: &'a Path) -> Option<(&'a Path, String)> {
+        let relative = path.strip_prefix(sysroot).ok()?;
+        Some((
+            relative.parent()?,
+            relative.file_name()?.to_string_lossy().to_string(),
+        ))
+    }
+
+    for exclusion in &report.excluded {
+        let Some((parent, name)) = split(sysroot, &exclusion.path) else {
+            continue;
+        };
+        if let Some(parent) = node_at(root, parent)
+            && let Some(node) = parent.children.get_mut(&name)
+            && !node.drop_module(&module_dir.join(&exclusion.module))
+        {
+            parent.children.remove(&name);
+        }
+    }
+
+    for failure in &report.failures {
+        let Some((parent, name)) = split(sysroot, &failure.path) else {
+            continue;
+        };
+        if let Some(parent) = node_at(root, parent)
+            && let Some(node) = parent.children.get_mut(&name)
+        {
+            node.skip = true;
+        }
+    }
+}
+
+fn save_report(report: &MountReport) {
+    if let Err(e) = report.save() {
+        log::warn!("failed to save mount report: {e:#}");
+    }
+}
+
+/// Records that no module was mounted in this boot, and why.
+pub fn skip_mount(reason: &str) -> MountReport {
+    let report = MountReport {
+        skipped: Some(reason.to_string()),
+        ..MountReport::default()
+    };
+    save_report(&report);
+    report
+}
+
+/// The tree saved by the last run.
+pub fn tree() -> Result<Node> {
+    let file = state_file(TREE_FILE);
+    let json = fs::read_to_string(&file).with_context(|| {
+        format!(
+            "failed to read {}, has magic mount run yet?",
+            file.display()
+        )
+    })?;
+    serde_json::from_str(&json).with_context(|| format!("failed to parse {}", file.display()))
+}
+
 pub fn magic_mount<P>(
     tmp_path: P,
+    sysroot: &Path,
     module_dir: &Path,
     mount_source: &str,
     extra_partitions: &[String],
-    #[cfg(any(target_os = "linux", target_os = "android"))] umount: bool,
-    #[cfg(not(any(target_os = "linux", target_os = "android")))] _umount: bool,
-) -> Result<()>
+    priority: &[String],
+    umount: bool,
+) -> Result<MountReport>
 where
     P: AsRef<Path>,
 {
-    if let Some(root) = collect_module_files(module_dir, extra_partitions)? {
+    if let Some(mut root) = collect_module_files(
+        sysroot,
+        module_dir,
+        extra_partitions,
+        priority,
+        &mut Vec::new(),
+    )? {
         log::debug!("collected: {root:?}");
-        std::thread::Builder::new()
-            .name("GetTree".to_string())
-            .spawn(|| -> Result<()> {
-                let output = std::process::Command::new("busybox")
-                    .args(["tree", "/data/adb/modules"])
-                    .output();
-
-                if output.is_err() {
-                    return Ok(());
-                }
-
-                log::debug!(
-                    "modules tree:\n{}",
-                    String::from_utf8_lossy(&output?.stdout)
-                );
-
-                Ok(())
-            })?;
+        mark_skipped(sysroot, &mut root);
+        if log::log_enabled!(log::Level::Debug) {
+            match render_module_tree(&root, module_dir) {
+                Ok(tree) => log::debug!("modules tree:\n{tree}"),
+                Err(e) => log::debug!("failed to render modules tree: {e:#}"),
+            }
+        }
 
+        let backend = RealBackend::new(umount);
         let tmp_root = tmp_path.as_ref();
         let tmp_dir = tmp_root.join("workdir");
-        ensure_dir_exists(&tmp_dir)?;
+        backend.create_dir_all(&tmp_dir)?;
 
-        mount(mount_source, &tmp_dir, "tmpfs", MountFlags::empty(), None).context("mount tmp")?;
-        mount_change(&tmp_dir, MountPropagationFlags::PRIVATE).context("make tmp private")?;
+        backend
+            .mount_tmpfs(mount_source, &tmp_dir)
+            .context("mount tmp")?;
+        backend.make_private(&tmp_dir).context("make tmp private")?;
 
+        catch_sigterm();
+        let journal = Journal::new(&backend, &tmp_dir);
+        let recorder = Recorder::default();
         let ret = MagicMount::new(
             &root,
-            Path::new("/"),
+            sysroot,
             tmp_dir.as_path(),
             false,
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            umount,
+            &journal,
+            &recorder,
         )
-        .do_mount();
+        .with_recovery(module_dir)
+        .do_mount()
+        .and_then(|()| check_terminated());
 
-        if let Err(e) = unmount(&tmp_dir, UnmountFlags::DETACH) {
+        if let Err(e) = backend.unmount(&tmp_dir) {
             log::error!("failed to unmount tmp {e}");
         }
-        #[cfg(any(target_os = "linux", target_os = "android"))]
-        {
-            LIST.lock().unwrap().flags(2);
-            LIST.lock()
-                .unwrap()
-                .format_msg(|p| format!("umount {p:?} successful"));
-            LIST.lock().unwrap().umount()?;
+        if ret.is_err() {
+            journal.rollback();
         }
-        fs::remove_dir(tmp_dir).ok();
-
-        let mounted_symbols = MOUNTDED_SYMBOLS_FILES.load(std::sync::atomic::Ordering::Relaxed);
-        let mounted_files = MOUNTDED_FILES.load(std::sync::atomic::Ordering::Relaxed);
-        log::info!("mounted files: {mounted_files}, mounted symlinks: {mounted_symbols}");
-        ret
+        flush_unmountable(umount)?;
+        backend.remove_dir(&tmp_dir).ok();
+
+        let report = recorder.report(sysroot, module_dir, &ret);
+        log::info!("{report}");
+        save_report(&report);
+        prune(&mut root, sysroot, module_dir, &report);
+        save_state(
+            tmp_path.as_ref(),
+            sysroot,
+            module_dir,
+            &mut root,
+            &recorder,
+            &ret,
+        );
+        ret.map(|()| report)
     } else {
         log::info!("no modules to mount, skipping!");
-        Ok(())
+        let report = MountReport {
+            success: true,
+            ..MountReport::default()
+        };
+        save_report(&report);
+        save_state(
+            tmp_path.as_ref(),
+            sysroot,
+            module_dir,
+            &mut Node::new_root(""),
+            &Recorder::default(),
+            &Ok(()),
+        );
+        Ok(report)
+    }
+}
+
+/// Dry run of `magic_mount`: collects the modules and returns every operation
+/// it would perform.
+pub fn plan<P>(
+    tmp_path: P,
+    sysroot: &Path,
+    module_dir: &Path,
+    extra_partitions: &[String],
+    priority: &[String],
+) -> Result<Vec<Operation>>
+where
+    P: AsRef<Path>,
+{
+    let Some(mut root) = collect_module_files(
+        sysroot,
+        module_dir,
+        extra_partitions,
+        priority,
+        &mut Vec::new(),
+    )?
+    else {
+        return Ok(Vec::new());
+    };
+    mark_skipped(sysroot, &mut root);
+    plan::plan(tmp_path, sysroot, module_dir, &root)
+}
+
+/// Every path shipped by more than one module, with the module that wins it.
+pub fn conflicts(
+    sysroot: &Path,
+    module_dir: &Path,
+    extra_partitions: &[String],
+    priority: &[String],
+) -> Result<Vec<Conflict>> {
+    let mut conflicts = Vec::new();
+    collect_module_files(
+        sysroot,
+        module_dir,
+        extra_partitions,
+        priority,
+        &mut conflicts,
+    )?;
+    Ok(conflicts)
+}
+
+/// Reverses the run recorded in the state file, if it happened in this boot.
+pub fn unmount() -> Result<()> {
+    let mut state = MountState::load()?;
+    if state.stale {
+        bail!("nothing was mounted in this boot");
+    }
+
+    let backend = RealBackend::new(false);
+    let mut failed = 0;
+    for target in state.teardown_order() {
+        match backend.unmount(&target) {
+            Ok(()) => log::info!("unmounted {}", target.display()),
+            Err(e) => {
+                log::error!("failed to unmount {}: {e:#}", target.display());
+                failed += 1;
+            }
+        }
+    }
+
+    state.clear();
+    state.save()?;
+
+    if failed > 0 {
+        bail!("{failed} mounts could not be unmounted");
     }
+    Ok(())
 }
@@ -0,0 +1,161 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    fs::DirEntry,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    defs::{AUFS_META_PREFIX, DISABLE_FILE_NAME, REMOVE_FILE_NAME, SKIP_MOUNT_FILE_NAME},
+    magic_mount::{
+        node::{Node, NodeFileType},
+        utils::collect_module_files,
+    },
+};
+
+/// Module paths that end up mounted, skipped subtrees left out.
+fn mounted_paths(node: &Node, paths: &mut HashSet<PathBuf>) {
+    if node.skip {
+        return;
+    }
+    if let Some(module_path) = &node.module_path {
+        paths.insert(module_path.clone());
+    }
+    for child in node.children.values() {
+        mounted_paths(child, paths);
+    }
+}
+
+fn sorted_entries(dir: &Path) -> Vec<DirEntry> {
+    let mut entries: Vec<_> = dir
+        .read_dir()
+        .map(|it| it.flatten().collect())
+        .unwrap_or_default();
+    entries.sort_by_key(DirEntry::file_name);
+    entries
+}
+
+/// Renders `entry` and everything below it, returns whether any of it is
+/// mounted. Children go first so a dir knows whether its content is mounted.
+fn render_entry(
+    entry: &DirEntry,
+    mounted: &HashSet<PathBuf>,
+    prefix: &str,
+    last: bool,
+) -> (String, bool) {
+    let (branch, indent) = if last {
+        ("└── ", "    ")
+    } else {
+        ("├── ", "│   ")
+    };
+    let name = entry.file_name().to_string_lossy().to_string();
+    let path = entry.path();
+    let Some(node) = Node::new_module(&name, entry) else {
+        let flag = if name.starts_with(AUFS_META_PREFIX) {
+            "aufs"
+        } else {
+            "unreadable"
+        };
+        return (format!("{prefix}{branch}{name} [{flag}]\n"), false);
+    };
+
+    let mut body = String::new();
+    let mut is_mounted = mounted.contains(&path);
+    if node.file_type == NodeFileType::Directory {
+        let entries = sorted_entries(&path);
+        let prefix = format!("{prefix}{indent}");
+        for (index, child) in entries.iter().enumerate() {
+            let (text, child_mounted) =
+                render_entry(child, mounted, &prefix, index + 1 == entries.len());
+            body.push_str(&text);
+            is_mounted |= child_mounted;
+        }
+    }
+
+    let mut flags = vec![node.file_type.label()];
+    if node.replace {
+        flags.push("replace");
+    }
+    if is_mounted {
+        flags.push("mounted");
+    }
+
+    (
+        format!("{prefix}{branch}{name} [{}]\n{body}", flags.join(", ")),
+        is_mounted,
+    )
+}
+
+/// Renders every module in `module_dir` like `tree` does, marking what each
+/// entry is and whether magic mount will mount it.
+pub fn module_tree(
+    sysroot: &Path,
+    module_dir: &Path,
+    extra_partitions: &[String],
+    priority: &[String],
+) -> Result<String> {
+    let root = collect_module_files(
+        sysroot,
+        module_dir,
+        extra_partitions,
+        priority,
+        &mut Vec::new(),
+    )?
+    .unwrap_or_else(|| Node::new_root(""));
+    render_module_tree(&root, module_dir)
+}
+
+/// Renders the modules in `module_dir` against `root`, the tree collected
+/// from them.
+pub fn render_module_tree(root: &Node, module_dir: &Path) -> Result<String> {
+    let mut mounted = HashSet::new();
+    mounted_paths(root, &mut mounted);
+
+    let mut out = String::new();
+    writeln!(out, "{}", module_dir.display())?;
+
+    // only dirs are modules, left out before the last one is picked
+    let modules: Vec<_> = sorted_entries(module_dir)
+        .into_iter()
+        .filter(|it| it.path().is_dir())
+        .collect();
+    for (index, module) in modules.iter().enumerate() {
+        let last = index + 1 == modules.len();
+        let path = module.path();
+
+        let mut flags = Vec::new();
+        for (file, flag) in [
+            (DISABLE_FILE_NAME, "disabled"),
+            (REMOVE_FILE_NAME, "removed"),
+            (SKIP_MOUNT_FILE_NAME, "skip_mount"),
+        ] {
+            if path.join(file).exists() {
+                flags.push(flag);
+            }
+        }
+
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        write!(out, "{branch}{}", module.file_name().to_string_lossy())?;
+        if !flags.is_empty() {
+            write!(out, " ({})", flags.join(", "))?;
+        }
+        writeln!(out)?;
+
+        let entries = sorted_entries(&path);
+        for (index, entry) in entries.iter().enumerate() {
+            let (text, _) = render_entry(entry, &mounted, indent, index + 1 == entries.len());
+            out.push_str(&text);
+        }
+    }
+
+    Ok(out)
+}
@@ -2,9 +2,9 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{BTreeMap, HashMap, hash_map::Entry},
     fmt,
-    fs::{DirEntry, FileType},
+    fs::{DirEntry, FileType, Metadata},
     os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
 };
@@ -12,15 +12,32 @@ use std::{
 use anyhow::Result;
 use extattr::lgetxattr;
 use rustix::path::Arg;
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::defs::{REPLACE_DIR_FILE_NAME, REPLACE_DIR_XATTR};
+use crate::{
+    defs::{
+        AUFS_META_PREFIX, AUFS_OPAQUE_FILE_NAME, AUFS_WHITEOUT_PREFIX, REPLACE_DIR_FILE_NAME,
+        REPLACE_DIR_XATTRS, TREE_FILE, WHITEOUT_XATTRS,
+    },
+    magic_mount::{
+        conflict::Conflicts,
+        contexts::FileContexts,
+        rules::{Mode, ModuleRules},
+        state::state_file,
+    },
+};
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum NodeFileType {
     RegularFile,
     Directory,
     Symlink,
     Whiteout,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
 }
 
 impl From<FileType> for NodeFileType {
@@ -31,34 +48,139 @@ impl From<FileType> for NodeFileType {
             Self::Directory
         } else if value.is_symlink() {
             Self::Symlink
+        } else if value.is_fifo() {
+            Self::Fifo
+        } else if value.is_char_device() {
+            Self::CharDevice
+        } else if value.is_block_device() {
+            Self::BlockDevice
         } else {
-            Self::Whiteout
+            Self::Socket
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl NodeFileType {
+    pub const fn label(&self) -> &'static str {
+        match self {
+            Self::RegularFile => "file",
+            Self::Directory => "dir",
+            Self::Symlink => "symlink",
+            Self::Whiteout => "whiteout",
+            Self::Fifo => "fifo",
+            Self::Socket => "socket",
+            Self::CharDevice => "char",
+            Self::BlockDevice => "block",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     pub name: String,
     pub file_type: NodeFileType,
+    #[serde(serialize_with = "sorted")]
     pub children: HashMap<String, Self>,
     // the module that owned this node
     pub module_path: Option<PathBuf>,
     pub replace: bool,
     pub skip: bool,
+    // rebuilt as a tmpfs, only known after mounting
+    #[serde(default)]
+    pub tmpfs: bool,
+    // label from the module's file_contexts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+fn sorted<S>(children: &HashMap<String, Node>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    children
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
 }
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "u need to send '/data/adb/magic_mount/tree' to developer "
+            "u need to send '{}' to developer ",
+            state_file(TREE_FILE).display()
         )
     }
 }
 
+/// Renders a node and everything below it, one line per node.
+pub struct Tree<'a>(pub &'a Node);
+
+impl Tree<'_> {
+    fn line(node: &Node, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if node.name.is_empty() {
+            "/"
+        } else {
+            &node.name
+        };
+        write!(f, "{name}")?;
+
+        let mut flags = Vec::new();
+        if node.file_type != NodeFileType::Directory {
+            flags.push(node.file_type.label());
+        }
+        if node.replace {
+            flags.push("replace");
+        }
+        if node.tmpfs {
+            flags.push("tmpfs");
+        }
+        if node.skip {
+            flags.push("skip");
+        }
+        if !flags.is_empty() {
+            write!(f, " [{}]", flags.join(", "))?;
+        }
+        if let Some(module_path) = &node.module_path {
+            write!(f, " <- {}", module_path.display())?;
+        }
+        if let Some(context) = &node.context {
+            write!(f, " ({context})")?;
+        }
+        writeln!(f)
+    }
+
+    fn children(node: &Node, f: &mut fmt::Formatter<'_>, prefix: &str) -> fmt::Result {
+        let children: BTreeMap<_, _> = node.children.iter().collect();
+        for (index, child) in children.values().enumerate() {
+            let (branch, indent) = if index + 1 == children.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            write!(f, "{prefix}{branch}")?;
+            Self::line(child, f)?;
+            Self::children(child, f, &format!("{prefix}{indent}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Tree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Self::line(self.0, f)?;
+        Self::children(self.0, f, "")
+    }
+}
+
 impl Node {
-    pub fn collect_module_files<P>(&mut self, module_dir: P) -> Result<bool>
+    pub fn collect_module_files<P>(
+        &mut self,
+        module_dir: P,
+        rules: &ModuleRules,
+        contexts: &FileContexts,
+        conflicts: &mut Conflicts,
+    ) -> Result<bool>
     where
         P: AsRef<Path>,
     {
@@ -66,15 +188,48 @@ impl Node {
         let mut has_file = false;
         for entry in dir.read_dir()?.flatten() {
             let name = entry.file_name().to_string_lossy().to_string();
+            let new = Self::new_module(&name, &entry);
+            // an aufs whiteout stands for the entry it hides
+            let name = new.as_ref().map_or(name, |it| it.name.clone());
+            let path = dir.join(&name);
+
+            if rules.excludes(&path) {
+                log::debug!("skipped {} by rules", path.display());
+                continue;
+            }
+            // a skipped dir is only walked for the paths included below it,
+            // so it must not replace what is already there
+            let skipped = rules.mode(&path) == Mode::Skip;
+            let new = new.map(|mut it| {
+                it.replace &= !skipped;
+                it.context = contexts.lookup(&path, &it.file_type);
+                it
+            });
 
             let node = match self.children.entry(name.clone()) {
-                Entry::Occupied(o) => Some(o.into_mut()),
-                Entry::Vacant(v) => Self::new_module(&name, &entry).map(|it| v.insert(it)),
+                Entry::Occupied(o) => {
+                    let node = o.into_mut();
+                    // modules are layered by priority: dirs are merged unless the
+                    // later one replaces it, anything else is overridden
+                    if let Some(new) = new
+                        && (node.file_type != NodeFileType::Directory
+                            || new.file_type != NodeFileType::Directory
+                            || new.replace)
+                    {
+                        if let (Some(loser), Some(winner)) = (&node.module_path, &new.module_path) {
+                            conflicts.record(winner, loser);
+                        }
+                        *node = new;
+                    }
+                    Some(node)
+                }
+                Entry::Vacant(v) => new.map(|it| v.insert(it)),
             };
 
             if let Some(node) = node {
                 has_file |= if node.file_type == NodeFileType::Directory {
-                    node.collect_module_files(dir.join(&node.name))? || node.replace
+                    node.collect_module_files(dir.join(&node.name), rules, contexts, conflicts)?
+                        || node.replace
                 } else {
                     true
                 }
@@ -84,17 +239,64 @@ impl Node {
         Ok(has_file)
     }
 
+    /// Removes every entry of the module at `module` below this node. Returns
+    /// false when nothing of the node is left.
+    pub fn drop_module(&mut self, module: &Path) -> bool {
+        self.children.retain(|_, child| child.drop_module(module));
+
+        let owned = self
+            .module_path
+            .as_deref()
+            .is_some_and(|it| it.starts_with(module));
+        if !owned {
+            return true;
+        }
+        if self.file_type != NodeFileType::Directory || self.children.is_empty() {
+            return false;
+        }
+
+        // other modules still ship entries in it, the dir becomes theirs
+        self.module_path = self
+            .children
+            .values()
+            .find_map(|it| it.module_path.as_deref()?.parent())
+            .map(Path::to_path_buf);
+        self.replace = false;
+        self.context = None;
+        true
+    }
+
     fn dir_is_replace<P>(path: P) -> bool
     where
         P: AsRef<Path>,
     {
-        if let Ok(v) = lgetxattr(&path, REPLACE_DIR_XATTR)
-            && String::from_utf8_lossy(&v) == "y"
+        if REPLACE_DIR_XATTRS
+            .iter()
+            .any(|xattr| lgetxattr(&path, xattr).is_ok_and(|v| v == b"y"))
         {
             return true;
         }
 
-        path.as_ref().join(REPLACE_DIR_FILE_NAME).exists()
+        let path = path.as_ref();
+        path.join(REPLACE_DIR_FILE_NAME).exists() || path.join(AUFS_OPAQUE_FILE_NAME).exists()
+    }
+
+    /// Whether `path` is a whiteout in any of the formats overlayfs knows: a
+    /// 0:0 char device or an empty file marked by xattr.
+    fn is_whiteout<P>(path: P, metadata: &Metadata) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        let file_type = metadata.file_type();
+        if file_type.is_char_device() {
+            return metadata.rdev() == 0;
+        }
+
+        file_type.is_file()
+            && metadata.len() == 0
+            && WHITEOUT_XATTRS
+                .iter()
+                .any(|xattr| lgetxattr(&path, xattr).is_ok())
     }
 
     pub fn new_root<S>(name: S) -> Self
@@ -108,16 +310,30 @@ impl Node {
             module_path: None,
             replace: false,
             skip: false,
+            tmpfs: false,
+            context: None,
         }
     }
 
+    /// The node for a module entry. An aufs `.wh.<name>` entry becomes a
+    /// whiteout named `<name>`, the other aufs metadata is left out.
     pub fn new_module<S>(name: &S, entry: &DirEntry) -> Option<Self>
     where
         S: ToString,
     {
+        let mut name = name.to_string();
+        if name.starts_with(AUFS_META_PREFIX) {
+            return None;
+        }
+        let aufs_whiteout =
+            name.starts_with(AUFS_WHITEOUT_PREFIX) && name.len() > AUFS_WHITEOUT_PREFIX.len();
+        if aufs_whiteout {
+            name.drain(..AUFS_WHITEOUT_PREFIX.len());
+        }
+
         if let Ok(metadata) = entry.metadata() {
             let path = entry.path();
-            let file_type = if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+            let file_type = if aufs_whiteout || Self::is_whiteout(&path, &metadata) {
                 Some(NodeFileType::Whiteout)
             } else {
                 Some(NodeFileType::from(metadata.file_type()))
@@ -128,12 +344,14 @@ impl Node {
                     log::debug!("{} need replace", path.display());
                 }
                 return Some(Self {
-                    name: name.to_string(),
+                    name,
                     file_type,
                     children: HashMap::default(),
                     module_path: Some(path),
                     replace,
                     skip: false,
+                    tmpfs: false,
+                    context: None,
                 });
             }
         }
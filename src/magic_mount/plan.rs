@@ -0,0 +1,164 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::magic_mount::{
+    MagicMount,
+    backend::{Call, RecordingBackend},
+    node::Node,
+    report::Recorder,
+};
+
+/// A single step `magic_mount` would take. The mounts come in execution
+/// order, the children that would fail after them.
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// the work dir `work` becomes a tmpfs of its own, later moved over the
+    /// real dir
+    Tmpfs {
+        work: PathBuf,
+    },
+    /// a directory is created inside the tmpfs
+    Dir {
+        target: PathBuf,
+    },
+    /// an empty file inside the tmpfs, a bind mount goes on top of it
+    File {
+        target: PathBuf,
+    },
+    Bind {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// a module file is copied into the work dir, to get the capabilities
+    /// of the stock file
+    Copy {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    Symlink {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// a device node is recreated inside the tmpfs
+    Mknod {
+        target: PathBuf,
+        dev: u64,
+    },
+    Move {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// `module` is left out of `target` and the rest mounted again
+    Exclude {
+        target: PathBuf,
+        module: String,
+        error: String,
+    },
+    /// a child that would fail to mount, `magic_mount` logs it and moves on
+    Error {
+        target: PathBuf,
+        module: Option<String>,
+        error: String,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tmpfs { work } => write!(f, "tmpfs     {}", work.display()),
+            Self::Dir { target } => write!(f, "dir       {}", target.display()),
+            Self::File { target } => write!(f, "file      {}", target.display()),
+            Self::Bind { source, target } => {
+                write!(f, "bind      {} -> {}", source.display(), target.display())
+            }
+            Self::Copy { source, target } => {
+                write!(f, "copy      {} -> {}", source.display(), target.display())
+            }
+            Self::Symlink { source, target } => {
+                write!(f, "symlink   {} -> {}", target.display(), source.display())
+            }
+            Self::Mknod { target, dev } => write!(f, "mknod     {} ({dev})", target.display()),
+            Self::Move { source, target } => {
+                write!(f, "move      {} -> {}", source.display(), target.display())
+            }
+            Self::Exclude {
+                target,
+                module,
+                error,
+            } => write!(f, "exclude   {module} from {}: {error}", target.display()),
+            Self::Error { target, error, .. } => {
+                write!(f, "error     {}: {error}", target.display())
+            }
+        }
+    }
+}
+
+impl Operation {
+    /// The step behind a backend call, none for the ones that only set
+    /// metadata or flags.
+    fn from_call(call: Call) -> Option<Self> {
+        Some(match call {
+            // a dir bound onto itself is how a work dir gets its own mount
+            Call::Bind(source, target) if source == target => Self::Tmpfs { work: target },
+            Call::Bind(source, target) => Self::Bind { source, target },
+            Call::CreateDir(target) => Self::Dir { target },
+            Call::CreateFile(target) => Self::File { target },
+            Call::CopyFile(source, target) => Self::Copy { source, target },
+            Call::Symlink(source, target) => Self::Symlink { source, target },
+            Call::Mknod(target, _, dev) => Self::Mknod { target, dev },
+            Call::Move(source, target) => Self::Move { source, target },
+            _ => return None,
+        })
+    }
+}
+
+/// Runs `MagicMount` over `root` against a backend that only records, so
+/// the plan takes every decision a `magic_mount` run with the same
+/// arguments would, recovery included, without touching the system.
+pub fn plan<P>(
+    tmp_path: P,
+    sysroot: &Path,
+    module_dir: &Path,
+    root: &Node,
+) -> Result<Vec<Operation>>
+where
+    P: AsRef<Path>,
+{
+    let tmp_dir = tmp_path.as_ref().join("workdir");
+    let backend = RecordingBackend::default();
+    let recorder = Recorder::default();
+
+    let ret = MagicMount::new(root, sysroot, tmp_dir.as_path(), false, &backend, &recorder)
+        .with_recovery(module_dir)
+        .do_mount();
+    let report = recorder.report(sysroot, module_dir, &ret);
+    ret?;
+
+    let mut ops: Vec<_> = backend
+        .calls
+        .into_inner()
+        .into_iter()
+        .filter_map(Operation::from_call)
+        .collect();
+    ops.extend(report.excluded.into_iter().map(|it| Operation::Exclude {
+        target: it.path,
+        module: it.module,
+        error: it.error,
+    }));
+    ops.extend(report.failures.into_iter().map(|it| Operation::Error {
+        target: it.path,
+        module: it.module,
+        error: it.error,
+    }));
+
+    Ok(ops)
+}
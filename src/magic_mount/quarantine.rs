@@ -0,0 +1,225 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs::{QUARANTINE_FILE_NAME, STRIKES_FILE},
+    magic_mount::{
+        blamed_module,
+        report::MountReport,
+        state::{state_file, write_state_file},
+    },
+};
+
+/// The marker `<module>/quarantine` that keeps a module from being mounted
+/// after it broke too many runs in a row. Updating the module replaces its
+/// dir and so lifts it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quarantine {
+    #[serde(default)]
+    pub module: String,
+    /// the last error the module caused
+    pub reason: String,
+    pub failures: u32,
+    /// unix time it was quarantined
+    pub time: u64,
+}
+
+impl fmt::Display for Quarantine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (failed {} times): {}",
+            self.module, self.failures, self.reason
+        )
+    }
+}
+
+/// Failed runs of a module not quarantined yet, kept in `STRIKES_FILE`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Strike {
+    pub failures: u32,
+    pub reason: String,
+}
+
+/// Whether the module at `module` is quarantined.
+pub fn is_quarantined(module: &Path) -> bool {
+    module.join(QUARANTINE_FILE_NAME).exists()
+}
+
+/// The quarantine of the module at `module`, if it has one.
+pub fn load_quarantine(module: &Path) -> Option<Quarantine> {
+    let file = module.join(QUARANTINE_FILE_NAME);
+    let json = fs::read_to_string(&file).ok()?;
+    let mut quarantine = serde_json::from_str(&json).unwrap_or_else(|e| {
+        log::warn!("failed to parse {}: {e}", file.display());
+        // a marker is a marker, even without a readable reason
+        Quarantine {
+            module: String::new(),
+            reason: "unknown".to_string(),
+            failures: 0,
+            time: 0,
+        }
+    });
+    quarantine.module = module
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    Some(quarantine)
+}
+
+/// Every quarantined module below `module_dir`.
+pub fn quarantined(module_dir: &Path) -> Result<Vec<Quarantine>> {
+    let mut list: Vec<_> = module_dir
+        .read_dir()
+        .with_context(|| format!("failed to read {}", module_dir.display()))?
+        .flatten()
+        .filter_map(|entry| load_quarantine(&entry.path()))
+        .collect();
+    list.sort_by(|a, b| a.module.cmp(&b.module));
+    Ok(list)
+}
+
+/// Lifts the quarantine of `ids`, or of every module if it is empty, and
+/// forgets their failures. Returns the modules that were released.
+pub fn clear_quarantine(module_dir: &Path, ids: &[String]) -> Result<Vec<String>> {
+    let mut released = Vec::new();
+    for quarantine in quarantined(module_dir)? {
+        if !ids.is_empty() && !ids.contains(&quarantine.module) {
+            continue;
+        }
+        let file = module_dir
+            .join(&quarantine.module)
+            .join(QUARANTINE_FILE_NAME);
+        fs::remove_file(&file).with_context(|| format!("failed to remove {}", file.display()))?;
+        released.push(quarantine.module);
+    }
+
+    let mut strikes = load_strikes();
+    strikes.retain(|id, _| !ids.is_empty() && !ids.contains(id));
+    save_strikes(&strikes)?;
+
+    Ok(released)
+}
+
+fn load_strikes() -> BTreeMap<String, Strike> {
+    fs::read_to_string(state_file(STRIKES_FILE))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn save_strikes(strikes: &BTreeMap<String, Strike>) -> Result<()> {
+    write_state_file(STRIKES_FILE, &serde_json::to_string_pretty(strikes)?)
+}
+
+/// The modules a run blames for something, with the last error of each. A
+/// run that failed was rolled back, so only the module it broke on is left.
+pub fn blamed(module_dir: &Path, result: &Result<MountReport>) -> BTreeMap<String, String> {
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            return blamed_module(module_dir, e)
+                .map(|id| (id, format!("{e:#}")))
+                .into_iter()
+                .collect();
+        }
+    };
+
+    let failures = report
+        .failures
+        .iter()
+        .filter_map(|it| Some((it.module.clone()?, it.error.clone())));
+    let excluded = report
+        .excluded
+        .iter()
+        .map(|it| (it.module.clone(), it.error.clone()));
+    failures.chain(excluded).collect()
+}
+
+/// Adds a strike to every module in `failing` and forgets the others, they
+/// got through a run. Returns the modules that reached `threshold`, they
+/// leave `strikes`.
+pub fn strike(
+    strikes: &mut BTreeMap<String, Strike>,
+    failing: BTreeMap<String, String>,
+    threshold: u32,
+) -> Vec<(String, Strike)> {
+    strikes.retain(|id, _| failing.contains_key(id));
+    for (id, reason) in failing {
+        let strike = strikes.entry(id).or_insert(Strike {
+            failures: 0,
+            reason: String::new(),
+        });
+        strike.failures += 1;
+        strike.reason = reason;
+    }
+
+    let out: Vec<_> = strikes
+        .iter()
+        .filter(|(_, it)| it.failures >= threshold)
+        .map(|(id, it)| (id.clone(), it.clone()))
+        .collect();
+    for (id, _) in &out {
+        strikes.remove(id);
+    }
+    out
+}
+
+/// Counts the modules the run with `result` blames and quarantines those that
+/// failed `threshold` runs in a row, 0 turns it off.
+pub fn update_quarantine(
+    module_dir: &Path,
+    result: &Result<MountReport>,
+    threshold: u32,
+) -> Result<()> {
+    if threshold == 0 {
+        return Ok(());
+    }
+
+    let mut strikes = load_strikes();
+    let failing = blamed(module_dir, result);
+    // nothing got through a failed run, so the others keep their strikes
+    let others: BTreeMap<_, _> = if result.is_err() {
+        strikes
+            .iter()
+            .filter(|(id, _)| !failing.contains_key(*id))
+            .map(|(id, it)| (id.clone(), it.clone()))
+            .collect()
+    } else {
+        BTreeMap::new()
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or_default();
+    let out = strike(&mut strikes, failing, threshold);
+    strikes.extend(others);
+    for (id, strike) in out {
+        log::warn!(
+            "quarantining module {id} after {} failed runs: {}",
+            strike.failures,
+            strike.reason
+        );
+        let quarantine = Quarantine {
+            module: id.clone(),
+            reason: strike.reason,
+            failures: strike.failures,
+            time,
+        };
+        let file = module_dir.join(&id).join(QUARANTINE_FILE_NAME);
+        fs::write(&file, serde_json::to_string_pretty(&quarantine)?)
+            .with_context(|| format!("failed to write {}", file.display()))?;
+    }
+    save_strikes(&strikes)
+}
@@ -0,0 +1,276 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs::REPORT_FILE,
+    magic_mount::state::{MountEntry, MountKind, module_id, state_file, write_state_file},
+};
+
+/// Something the engine did to one path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Bind,
+    Symlink,
+    Device,
+    Whiteout,
+    Tmpfs,
+    Mirror,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    pub binds: u32,
+    pub symlinks: u32,
+    pub devices: u32,
+    pub whiteouts: u32,
+    pub tmpfs: u32,
+    pub mirrors: u32,
+}
+
+impl Counts {
+    const fn add(&mut self, event: Event) {
+        let count = match event {
+            Event::Bind => &mut self.binds,
+            Event::Symlink => &mut self.symlinks,
+            Event::Device => &mut self.devices,
+            Event::Whiteout => &mut self.whiteouts,
+            Event::Tmpfs => &mut self.tmpfs,
+            Event::Mirror => &mut self.mirrors,
+        };
+        *count += 1;
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "binds: {}, symlinks: {}, devices: {}, whiteouts: {}, tmpfs: {}, mirrors: {}",
+            self.binds, self.symlinks, self.devices, self.whiteouts, self.tmpfs, self.mirrors
+        )
+    }
+}
+
+/// A child that failed to mount without failing the run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildFailure {
+    pub path: PathBuf,
+    pub module: Option<String>,
+    pub error: String,
+}
+
+/// A module left out of a subtree because mounting it there failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exclusion {
+    pub path: PathBuf,
+    pub module: String,
+    pub error: String,
+}
+
+/// What a `magic_mount` run did, saved to `REPORT_FILE`. The counts of a
+/// failed run cover what was mounted before it got rolled back.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MountReport {
+    pub success: bool,
+    pub error: Option<String>,
+    pub total: Counts,
+    pub modules: BTreeMap<String, Counts>,
+    pub partitions: BTreeMap<String, Counts>,
+    pub failures: Vec<ChildFailure>,
+    #[serde(default)]
+    pub excluded: Vec<Exclusion>,
+    /// why nothing was mounted at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+}
+
+impl fmt::Display for MountReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(reason) = &self.skipped {
+            return write!(f, "skipped: {reason}");
+        }
+        write!(f, "{}", self.total)?;
+        if !self.failures.is_empty() {
+            write!(f, ", failed children: {}", self.failures.len())?;
+        }
+        for exclusion in &self.excluded {
+            write!(
+                f,
+                ", excluded {} from {}",
+                exclusion.module,
+                exclusion.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What one run did and left mounted, filled in as it goes. Every
+/// `MagicMount` of the run shares it, so separate runs never mix.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    events: RefCell<Vec<(Event, PathBuf, Option<PathBuf>)>>,
+    failures: RefCell<Vec<(PathBuf, Option<PathBuf>, String)>>,
+    excluded: RefCell<Vec<Exclusion>>,
+    mounts: RefCell<Vec<(PathBuf, MountKind, Option<PathBuf>)>>,
+    tmpfs: RefCell<Vec<PathBuf>>,
+}
+
+impl Recorder {
+    /// Notes `event` at `path`, caused by the module entry at `module_path`.
+    pub fn record(&self, event: Event, path: &Path, module_path: Option<&Path>) {
+        self.events.borrow_mut().push((
+            event,
+            path.to_path_buf(),
+            module_path.map(Path::to_path_buf),
+        ));
+    }
+
+    /// Notes a child of `path` that failed to mount and was skipped.
+    pub fn record_failure(&self, path: &Path, module_path: Option<&Path>, error: &anyhow::Error) {
+        self.failures.borrow_mut().push((
+            path.to_path_buf(),
+            module_path.map(Path::to_path_buf),
+            format!("{error:#}"),
+        ));
+    }
+
+    /// Notes `module` left out of the subtree at `path`, after it broke it.
+    pub fn record_excluded(&self, path: &Path, module: &str, error: &anyhow::Error) {
+        self.excluded.borrow_mut().push(Exclusion {
+            path: path.to_path_buf(),
+            module: module.to_string(),
+            error: format!("{error:#}"),
+        });
+    }
+
+    /// Notes a module entry mounted at `target`.
+    pub fn record_mount(&self, target: &Path, kind: MountKind, source: Option<&Path>) {
+        self.mounts
+            .borrow_mut()
+            .push((target.to_path_buf(), kind, source.map(Path::to_path_buf)));
+    }
+
+    /// Notes a tmpfs moved over `target`.
+    pub fn record_tmpfs(&self, target: &Path) {
+        self.tmpfs.borrow_mut().push(target.to_path_buf());
+    }
+
+    /// Drops the events and mounts at or below `root`, after mounting it
+    /// failed.
+    pub fn forget(&self, root: &Path) {
+        self.events
+            .borrow_mut()
+            .retain(|(_, path, _)| !path.starts_with(root));
+        self.mounts
+            .borrow_mut()
+            .retain(|(target, ..)| !target.starts_with(root));
+        self.tmpfs.borrow_mut().retain(|it| !it.starts_with(root));
+    }
+
+    /// Everything the run did, in order.
+    pub fn events(&self) -> Vec<(Event, PathBuf, Option<PathBuf>)> {
+        self.events.borrow().clone()
+    }
+
+    /// The module entries and tmpfs dirs the run mounted.
+    pub fn mounts(&self, module_dir: &Path) -> (Vec<MountEntry>, Vec<PathBuf>) {
+        let mounts = self
+            .mounts
+            .borrow()
+            .iter()
+            .map(|(target, kind, source)| MountEntry {
+                target: target.clone(),
+                kind: *kind,
+                module: source.as_deref().and_then(|it| module_id(module_dir, it)),
+                source: source.clone(),
+            })
+            .collect();
+        (mounts, self.tmpfs.borrow().clone())
+    }
+
+    /// Builds the report of the run from what it recorded.
+    pub fn report(&self, sysroot: &Path, module_dir: &Path, result: &Result<()>) -> MountReport {
+        MountReport {
+            excluded: self.excluded.borrow().clone(),
+            ..MountReport::build(
+                &self.events.borrow(),
+                self.failures.borrow().clone(),
+                sysroot,
+                module_dir,
+                result,
+            )
+        }
+    }
+}
+
+fn partition(sysroot: &Path, path: &Path) -> Option<String> {
+    match path.strip_prefix(sysroot).ok()?.components().next() {
+        Some(Component::Normal(name)) => Some(name.to_string_lossy().to_string()),
+        _ => None,
+    }
+}
+
+impl MountReport {
+    pub fn build(
+        events: &[(Event, PathBuf, Option<PathBuf>)],
+        failures: Vec<(PathBuf, Option<PathBuf>, String)>,
+        sysroot: &Path,
+        module_dir: &Path,
+        result: &Result<()>,
+    ) -> Self {
+        let mut report = Self {
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            ..Self::default()
+        };
+
+        for (event, path, module_path) in events {
+            report.total.add(*event);
+            if let Some(id) = module_path
+                .as_deref()
+                .and_then(|it| module_id(module_dir, it))
+            {
+                report.modules.entry(id).or_default().add(*event);
+            }
+            if let Some(partition) = partition(sysroot, path) {
+                report.partitions.entry(partition).or_default().add(*event);
+            }
+        }
+
+        report.failures = failures
+            .into_iter()
+            .map(|(path, module_path, error)| ChildFailure {
+                module: module_path
+                    .as_deref()
+                    .and_then(|it| module_id(module_dir, it)),
+                path,
+                error,
+            })
+            .collect();
+
+        report
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_state_file(REPORT_FILE, &serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load() -> Result<Self> {
+        let file = state_file(REPORT_FILE);
+        let json = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read {}", file.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("failed to parse {}", file.display()))
+    }
+}
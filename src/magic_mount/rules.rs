@@ -0,0 +1,130 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs::RULES_DIR,
+    magic_mount::{state::state_file, utils::device_path},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Magic,
+    Skip,
+}
+
+/// User rules for one module, read from `<RULES_DIR>/<id>.toml` in the state
+/// dir of the sysroot:
+///
+/// ```toml
+/// default_mode = "magic"
+///
+/// [paths]
+/// "/vendor" = "skip"
+/// "/system/bin/foo" = "skip"
+/// ```
+///
+/// The longest matching path decides, anything unmatched uses `default_mode`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ModuleRules {
+    #[serde(default)]
+    pub default_mode: Mode,
+    #[serde(default)]
+    pub paths: BTreeMap<PathBuf, Mode>,
+    /// the module dir the rules are resolved against
+    #[serde(skip)]
+    pub module: PathBuf,
+}
+
+impl ModuleRules {
+    /// Loads the rules of the module at `module`, a missing file means no rules.
+    pub fn load<P>(module: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let module = module.as_ref();
+        let id = module.file_name().unwrap_or_default().to_string_lossy();
+        let file = state_file(RULES_DIR).join(format!("{id}.toml"));
+
+        let mut rules = if file.exists() {
+            let content = fs::read_to_string(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+            toml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", file.display()))?
+        } else {
+            Self::default()
+        };
+        rules.module = module.to_path_buf();
+
+        Ok(rules)
+    }
+
+    /// Like `load`, but broken rules only cost a warning.
+    pub fn load_or_default<P>(module: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self::load(&module).unwrap_or_else(|e| {
+            log::warn!("ignoring rules: {e:#}");
+            Self {
+                module: module.as_ref().to_path_buf(),
+                ..Self::default()
+            }
+        })
+    }
+
+    /// Maps `<module>/<partition>/...` to the path it targets, the same way
+    /// `FileContexts` does.
+    fn target(&self, module_path: &Path) -> Option<PathBuf> {
+        module_path.strip_prefix(&self.module).ok().map(device_path)
+    }
+
+    fn rule(path: &Path) -> PathBuf {
+        // allow both "/system/bin" and "system/bin/", and "/system/vendor"
+        // for "/vendor"
+        device_path(
+            &path
+                .components()
+                .filter(|it| matches!(it, Component::Normal(_)))
+                .collect::<PathBuf>(),
+        )
+    }
+
+    pub fn mode(&self, module_path: &Path) -> Mode {
+        let Some(target) = self.target(module_path) else {
+            return self.default_mode;
+        };
+
+        self.paths
+            .iter()
+            .map(|(path, mode)| (Self::rule(path), *mode))
+            .filter(|(path, _)| target.starts_with(path))
+            .max_by_key(|(path, _)| path.components().count())
+            .map_or(self.default_mode, |(_, mode)| mode)
+    }
+
+    /// Whether nothing at or below `module_path` gets mounted.
+    pub fn excludes(&self, module_path: &Path) -> bool {
+        if self.mode(module_path) == Mode::Magic {
+            return false;
+        }
+        let Some(target) = self.target(module_path) else {
+            return true;
+        };
+
+        !self
+            .paths
+            .iter()
+            .any(|(path, mode)| *mode == Mode::Magic && Self::rule(path).starts_with(&target))
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs::{BOOT_ID_FILE, STATE_DIR, STATE_FILE},
+    magic_mount::{
+        node::Node,
+        report::{Event, Recorder},
+    },
+};
+
+static SYSROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the files the runs leave behind in `STATE_DIR` of `sysroot`, so one
+/// against another root never touches the ones of the device.
+pub fn configure_state_dir(sysroot: &Path) {
+    let _ = SYSROOT.set(sysroot.to_path_buf());
+}
+
+/// The file `name` in the state dir.
+pub fn state_file(name: &str) -> PathBuf {
+    SYSROOT
+        .get()
+        .map_or_else(|| Path::new("/"), PathBuf::as_path)
+        .join(STATE_DIR)
+        .join(name)
+}
+
+/// Writes the state file `name`, creating the state dir in a fresh root.
+pub fn write_state_file(name: &str, content: &str) -> Result<()> {
+    let file = state_file(name);
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    fs::write(&file, content).with_context(|| format!("failed to write {}", file.display()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountKind {
+    File,
+    Symlink,
+    Device,
+}
+
+/// One module entry that ended up on the real tree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountEntry {
+    pub target: PathBuf,
+    pub kind: MountKind,
+    pub module: Option<String>,
+    pub source: Option<PathBuf>,
+}
+
+impl fmt::Display for MountEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            MountKind::File => "file",
+            MountKind::Symlink => "symlink",
+            MountKind::Device => "device",
+        };
+        write!(
+            f,
+            "{kind:<8} {} ({})",
+            self.target.display(),
+            self.module.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+/// What the last `magic_mount` run left mounted, saved to `STATE_FILE`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MountState {
+    /// unix time of the run
+    pub time: u64,
+    pub boot_id: String,
+    /// set on load when `boot_id` is not the current boot
+    #[serde(skip_deserializing)]
+    pub stale: bool,
+    pub success: bool,
+    pub error: Option<String>,
+    /// the tmpfs `main` mounts for the work dir
+    pub tempdir: Option<PathBuf>,
+    /// ids of the modules that are mounted
+    pub modules: Vec<String>,
+    /// dirs rebuilt as a tmpfs and moved over the real one
+    pub tmpfs: Vec<PathBuf>,
+    pub mounts: Vec<MountEntry>,
+    /// modules behind every independently mounted subtree, see `units`
+    #[serde(default)]
+    pub units: BTreeMap<PathBuf, Vec<String>>,
+    /// what mounting them took, so a sync can report on all of them
+    #[serde(default)]
+    pub events: Vec<(Event, PathBuf, Option<PathBuf>)>,
+}
+
+impl fmt::Display for MountState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.stale {
+            writeln!(f, "state is from a previous boot!")?;
+        }
+        match &self.error {
+            Some(e) => writeln!(f, "last run failed: {e}")?,
+            None => writeln!(f, "last run succeeded")?,
+        }
+        writeln!(f, "modules: {}", self.modules.join(", "))?;
+        for tmpfs in &self.tmpfs {
+            writeln!(f, "{:<8} {}", "tmpfs", tmpfs.display())?;
+        }
+        for entry in &self.mounts {
+            writeln!(f, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+fn boot_id() -> String {
+    fs::read_to_string(BOOT_ID_FILE)
+        .map(|it| it.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Splits the tree into the subtrees that are mounted independently of each
+/// other, the first module owned node on every path, and the modules behind
+/// each of them. Skipped nodes are never mounted, so they are left out.
+pub fn units(root: &Node, sysroot: &Path, module_dir: &Path) -> BTreeMap<PathBuf, Vec<String>> {
+    fn modules(node: &Node, module_dir: &Path, ids: &mut Vec<String>) {
+        if let Some(id) = node
+            .module_path
+            .as_deref()
+            .and_then(|it| module_id(module_dir, it))
+            && !ids.contains(&id)
+        {
+            ids.push(id);
+        }
+        for child in node.children.values().filter(|it| !it.skip) {
+            modules(child, module_dir, ids);
+        }
+    }
+
+    fn walk(
+        node: &Node,
+        path: &Path,
+        module_dir: &Path,
+        units: &mut BTreeMap<PathBuf, Vec<String>>,
+    ) {
+        for (name, child) in node.children.iter().filter(|(_, it)| !it.skip) {
+            let path = path.join(name);
+            if child.module_path.is_some() {
+                let mut ids = Vec::new();
+                modules(child, module_dir, &mut ids);
+                ids.sort();
+                units.insert(path, ids);
+            } else {
+                walk(child, &path, module_dir, units);
+            }
+        }
+    }
+
+    let mut units = BTreeMap::new();
+    walk(root, sysroot, module_dir, &mut units);
+    units
+}
+
+impl MountState {
+    /// Builds the state from what the run recorded. A failed run has been
+    /// rolled back, so it is left with nothing mounted.
+    pub fn collect(
+        tempdir: &Path,
+        sysroot: &Path,
+        module_dir: &Path,
+        root: &Node,
+        recorder: &Recorder,
+        result: &Result<()>,
+    ) -> Self {
+        let mut state = Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|it| it.as_secs())
+                .unwrap_or_default(),
+            boot_id: boot_id(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            tempdir: Some(tempdir.to_path_buf()),
+            ..Self::default()
+        };
+        if result.is_err() {
+            return state;
+        }
+
+        (state.mounts, state.tmpfs) = recorder.mounts(module_dir);
+        state.events = recorder.events();
+        state.units = units(root, sysroot, module_dir);
+        state.update_modules();
+
+        state
+    }
+
+    fn update_modules(&mut self) {
+        self.modules = self.units.values().flatten().cloned().collect();
+        self.modules.sort();
+        self.modules.dedup();
+    }
+
+    fn under_tmpfs(&self, path: &Path) -> bool {
+        self.tmpfs
+            .iter()
+            .any(|it| path != it && path.starts_with(it))
+    }
+
+    /// The mount points at or below `root` to detach, newest first. Mounts
+    /// inside a tmpfs dir go away with it.
+    pub fn mounts_under(&self, root: &Path) -> Vec<PathBuf> {
+        let mut order: Vec<_> = self
+            .mounts
+            .iter()
+            .rev()
+            .filter(|it| it.kind == MountKind::File && !self.under_tmpfs(&it.target))
+            .map(|it| it.target.clone())
+            .filter(|it| it.starts_with(root))
+            .collect();
+        order.extend(
+            self.tmpfs
+                .iter()
+                .rev()
+                .filter(|it| !self.under_tmpfs(it) && it.starts_with(root))
+                .cloned(),
+        );
+        order
+    }
+
+    /// Every mount point to detach, newest first, the work dir tmpfs last.
+    pub fn teardown_order(&self) -> Vec<PathBuf> {
+        let mut order = self.mounts_under(Path::new("/"));
+        order.extend(self.tempdir.clone());
+        order
+    }
+
+    /// Forgets the unit at `root`, after it has been unmounted.
+    pub fn forget(&mut self, root: &Path) {
+        self.mounts.retain(|it| !it.target.starts_with(root));
+        self.tmpfs.retain(|it| !it.starts_with(root));
+        self.events.retain(|(_, path, _)| !path.starts_with(root));
+        self.units.remove(root);
+        self.update_modules();
+    }
+
+    /// Adds the unit at `root` with what mounting it recorded.
+    pub fn absorb(
+        &mut self,
+        root: &Path,
+        modules: Vec<String>,
+        module_dir: &Path,
+        recorder: &Recorder,
+    ) {
+        let (mounts, tmpfs) = recorder.mounts(module_dir);
+        self.mounts.extend(mounts);
+        self.tmpfs.extend(tmpfs);
+        self.events.extend(recorder.events());
+        self.units.insert(root.to_path_buf(), modules);
+        self.update_modules();
+    }
+
+    /// Forgets everything mounted, after a teardown.
+    pub fn clear(&mut self) {
+        self.tempdir = None;
+        self.modules.clear();
+        self.units.clear();
+        self.tmpfs.clear();
+        self.mounts.clear();
+        self.events.clear();
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_state_file(STATE_FILE, &serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load() -> Result<Self> {
+        let file = state_file(STATE_FILE);
+        let json = fs::read_to_string(&file).with_context(|| {
+            format!(
+                "failed to read {}, has magic mount run yet?",
+                file.display()
+            )
+        })?;
+        let mut state: Self = serde_json::from_str(&json)
+            .with_context(|| format!("failed to parse {}", file.display()))?;
+        state.stale = state.boot_id != boot_id();
+        Ok(state)
+    }
+}
+
+pub fn module_id(module_dir: &Path, source: &Path) -> Option<String> {
+    match source.strip_prefix(module_dir).ok()?.components().next() {
+        Some(Component::Normal(id)) => Some(id.to_string_lossy().to_string()),
+        _ => None,
+    }
+}
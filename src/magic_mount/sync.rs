@@ -0,0 +1,277 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+
+use crate::magic_mount::{
+    MagicMount,
+    backend::{MountBackend, RealBackend},
+    blamed_module, flush_unmountable,
+    journal::{Journal, catch_sigterm, check_terminated},
+    mark_skipped,
+    node::Node,
+    prune,
+    report::{ChildFailure, MountReport, Recorder},
+    save_report, save_tree,
+    state::{MountState, units},
+    utils::collect_module_files,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncAction {
+    Mount,
+    Remount,
+    Unmount,
+}
+
+/// One subtree brought in line with the modules, reported as soon as it is done.
+#[derive(Debug, Serialize)]
+pub struct SyncStep {
+    pub path: PathBuf,
+    pub action: SyncAction,
+    /// the modules mounted there now
+    pub modules: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for SyncStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            SyncAction::Mount => "mount",
+            SyncAction::Remount => "remount",
+            SyncAction::Unmount => "unmount",
+        };
+        write!(
+            f,
+            "{action:<8} {} ({})",
+            self.path.display(),
+            self.modules.join(", ")
+        )?;
+        if let Some(e) = &self.error {
+            write!(f, " failed: {e}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn node_at<'a>(root: &'a mut Node, path: &Path) -> Option<&'a mut Node> {
+    let mut node = root;
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            node = node.children.get_mut(name.to_str()?)?;
+        }
+    }
+    Some(node)
+}
+
+/// Detaches whatever is mounted at the unit `path` and mounts its fresh
+/// subtree from `root`, if there is one. `root` went through `mark_skipped`.
+fn sync_unit<B>(
+    backend: &B,
+    work_dir: &Path,
+    sysroot: &Path,
+    root: &mut Node,
+    path: &Path,
+    state: &mut MountState,
+    recorder: &Recorder,
+) -> Result<()>
+where
+    B: MountBackend,
+{
+    for target in state.mounts_under(path) {
+        backend
+            .unmount(&target)
+            .with_context(|| format!("unmount {}", target.display()))?;
+    }
+    state.forget(path);
+
+    let Some(parent_path) = path.parent() else {
+        return Ok(());
+    };
+    let Some(parent) = parent_path
+        .strip_prefix(sysroot)
+        .ok()
+        .and_then(|it| node_at(root, it))
+    else {
+        return Ok(());
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(node) = parent.children.get(name.as_ref()) else {
+        return Ok(());
+    };
+    if node.skip {
+        return Ok(());
+    }
+
+    let work_dir = work_dir.join(parent_path.strip_prefix("/").unwrap_or(parent_path));
+    let journal = Journal::new(backend, work_dir.as_path());
+    let ret = MagicMount::new(
+        node,
+        parent_path,
+        work_dir.as_path(),
+        false,
+        &journal,
+        recorder,
+    )
+    .do_mount()
+    .and_then(|()| check_terminated());
+    if ret.is_err() {
+        journal.rollback();
+    }
+    ret
+}
+
+/// The report of everything mounted after a sync: the counts of `state`, the
+/// failures of the `changed` units and those `previous` had everywhere else.
+pub fn synced_report(
+    state: &MountState,
+    previous: &MountReport,
+    changed: &BTreeSet<PathBuf>,
+    failures: Vec<ChildFailure>,
+    sysroot: &Path,
+    module_dir: &Path,
+) -> MountReport {
+    let kept = |path: &Path| !changed.iter().any(|it| path.starts_with(it));
+    MountReport {
+        failures: previous
+            .failures
+            .iter()
+            .filter(|it| kept(&it.path))
+            .cloned()
+            .chain(failures)
+            .collect(),
+        excluded: previous
+            .excluded
+            .iter()
+            .filter(|it| kept(&it.path))
+            .cloned()
+            .collect(),
+        ..MountReport::build(&state.events, Vec::new(), sysroot, module_dir, &Ok(()))
+    }
+}
+
+/// Brings the mounts of this boot in line with the modules as they are now,
+/// remounting only the subtrees whose modules changed. `report` is called
+/// after every subtree.
+pub fn sync<F>(
+    sysroot: &Path,
+    module_dir: &Path,
+    mount_source: &str,
+    extra_partitions: &[String],
+    priority: &[String],
+    umount: bool,
+    mut report: F,
+) -> Result<()>
+where
+    F: FnMut(&SyncStep),
+{
+    let mut state = MountState::load()?;
+    if state.stale {
+        bail!("nothing was mounted in this boot, cannot sync");
+    }
+    let Some(tempdir) = state.tempdir.clone() else {
+        bail!("no temp dir recorded, cannot sync");
+    };
+
+    let mut root = collect_module_files(
+        sysroot,
+        module_dir,
+        extra_partitions,
+        priority,
+        &mut Vec::new(),
+    )?
+    .unwrap_or_else(|| Node::new_root(""));
+    // a unit that cannot be mounted must not count as changed
+    mark_skipped(sysroot, &mut root);
+    let fresh = units(&root, sysroot, module_dir);
+    let changed: BTreeSet<_> = state
+        .units
+        .keys()
+        .chain(fresh.keys())
+        .filter(|it| state.units.get(*it) != fresh.get(*it))
+        .cloned()
+        .collect();
+
+    if changed.is_empty() {
+        log::info!("already in sync");
+        return Ok(());
+    }
+
+    let backend = RealBackend::new(umount);
+    let tmp_dir = tempdir.join("workdir");
+    backend.create_dir_all(&tmp_dir)?;
+    backend
+        .mount_tmpfs(mount_source, &tmp_dir)
+        .context("mount tmp")?;
+    backend.make_private(&tmp_dir).context("make tmp private")?;
+    catch_sigterm();
+
+    let mut failures = Vec::new();
+    for path in &changed {
+        let modules = fresh.get(path).cloned();
+        let action = match (state.units.contains_key(path), &modules) {
+            (false, _) => SyncAction::Mount,
+            (true, Some(_)) => SyncAction::Remount,
+            (true, None) => SyncAction::Unmount,
+        };
+
+        let recorder = Recorder::default();
+        let ret = sync_unit(
+            &backend, &tmp_dir, sysroot, &mut root, path, &mut state, &recorder,
+        );
+        if let (Ok(()), Some(modules)) = (&ret, &modules) {
+            state.absorb(path, modules.clone(), module_dir, &recorder);
+        }
+        if let Err(e) = &ret {
+            log::error!("failed to sync {}: {e:#}", path.display());
+            failures.push(ChildFailure {
+                path: path.clone(),
+                module: blamed_module(module_dir, e),
+                error: format!("{e:#}"),
+            });
+        }
+
+        report(&SyncStep {
+            path: path.clone(),
+            action,
+            modules: if ret.is_ok() {
+                modules.unwrap_or_default()
+            } else {
+                Vec::new()
+            },
+            error: ret.err().map(|e| format!("{e:#}")),
+        });
+    }
+
+    if let Err(e) = backend.unmount(&tmp_dir) {
+        log::error!("failed to unmount tmp {e}");
+    }
+    flush_unmountable(umount)?;
+    backend.remove_dir(&tmp_dir).ok();
+    state.save()?;
+
+    // the report and tree describe what is mounted now, like after a full run
+    let failed = failures.len();
+    let previous = MountReport::load().unwrap_or_default();
+    let mount_report = synced_report(&state, &previous, &changed, failures, sysroot, module_dir);
+    log::info!("{mount_report}");
+    save_report(&mount_report);
+    prune(&mut root, sysroot, module_dir, &mount_report);
+    if let Err(e) = save_tree(&mut root, sysroot, &state.tmpfs) {
+        log::warn!("failed to save node tree: {e:#}");
+    }
+
+    if failed > 0 {
+        bail!("{failed} subtrees failed to sync");
+    }
+    Ok(())
+}
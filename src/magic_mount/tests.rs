@@ -0,0 +1,1108 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::magic_mount::{
+    MagicMount,
+    backend::{Call, MountBackend, RecordingBackend},
+    boot_guard::{BootGuard, BootVerdict},
+    conflict::Conflicts,
+    contexts::FileContexts,
+    journal::{Journal, catch_sigterm, reset_terminated},
+    known_good::{KnownGood, fingerprint},
+    mark_skipped, module_tree,
+    module_tree::render_module_tree,
+    node::{Node, NodeFileType},
+    prune,
+    quarantine::{Strike, blamed, strike},
+    report::{ChildFailure, Event, MountReport, Recorder},
+    rules::{Mode, ModuleRules},
+    state::{MountEntry, MountKind, MountState, units},
+    sync::synced_report,
+    utils::{collect_module_files, sort_by_priority},
+    xattr::{XattrPolicy, carry_caps, copy_xattrs},
+};
+
+/// Held by the tests that mount through `check_terminated`, the SIGTERM flag
+/// is process wide.
+static SIGTERM: Mutex<()> = Mutex::new(());
+
+/// A stock tree and a module dir under a scratch dir, removed on drop.
+struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("mmrs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("stock")).unwrap();
+        fs::create_dir_all(root.join("module")).unwrap();
+        fs::create_dir_all(root.join("work")).unwrap();
+        Self { root }
+    }
+
+    fn stock(&self) -> PathBuf {
+        self.root.join("stock")
+    }
+
+    fn module(&self) -> PathBuf {
+        self.root.join("module")
+    }
+
+    fn work(&self) -> PathBuf {
+        self.root.join("work")
+    }
+
+    fn file<P>(&self, path: P)
+    where
+        P: AsRef<Path>,
+    {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    /// Collects the module like `collect_module_files` does for `system`.
+    fn collect(&self) -> Node {
+        let mut system = Node::new_root("system");
+        system
+            .collect_module_files(
+                self.module().join("system"),
+                &ModuleRules::default(),
+                &FileContexts::load(self.module()).unwrap(),
+                &mut Conflicts::new(self.module()),
+            )
+            .unwrap();
+        let mut root = Node::new_root("");
+        root.children.insert("system".to_string(), system);
+        root
+    }
+
+    /// Layers the `system` of the module dirs `modules` under the scratch dir.
+    fn collect_modules(&self, modules: &[&str]) -> Node {
+        let mut system = Node::new_root("system");
+        for module in modules {
+            system
+                .collect_module_files(
+                    self.root.join(module).join("system"),
+                    &ModuleRules::default(),
+                    &FileContexts::default(),
+                    &mut Conflicts::new(&self.root),
+                )
+                .unwrap();
+        }
+        let mut root = Node::new_root("");
+        root.children.insert("system".to_string(), system);
+        root
+    }
+
+    fn mount(&self, root: &Node) -> Vec<Call> {
+        self.mount_into(root, &Recorder::default())
+    }
+
+    fn mount_into(&self, root: &Node, recorder: &Recorder) -> Vec<Call> {
+        let backend = RecordingBackend::default();
+        MagicMount::new(root, self.stock(), self.work(), false, &backend, recorder)
+            .do_mount()
+            .unwrap();
+        backend.calls.into_inner()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn moved(calls: &[Call]) -> Vec<PathBuf> {
+    calls
+        .iter()
+        .filter_map(|call| match call {
+            Call::Move(_, target) => Some(target.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn new_file_turns_parent_into_tmpfs() {
+    let fixture = Fixture::new("new-file");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("module/system/bin/foo");
+
+    let calls = fixture.mount(&fixture.collect());
+
+    let work = fixture.work().join("system/bin");
+    assert_eq!(moved(&calls), [fixture.stock().join("system/bin")]);
+    assert!(calls.contains(&Call::Bind(work.clone(), work.clone())));
+    assert!(calls.contains(&Call::Bind(
+        fixture.module().join("system/bin/foo"),
+        work.join("foo")
+    )));
+    // the stock file is mirrored into the new tmpfs
+    assert!(calls.contains(&Call::Bind(
+        fixture.stock().join("system/bin/sh"),
+        work.join("sh")
+    )));
+}
+
+#[test]
+fn plan_lists_what_a_run_would_mount() {
+    let fixture = Fixture::new("plan");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("modules/ma/module.prop");
+    fixture.file("modules/ma/system/bin/foo");
+    let modules = fixture.root.join("modules");
+
+    let ops = crate::magic_mount::plan(fixture.work(), &fixture.stock(), &modules, &[], &[])
+        .unwrap()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    let work = fixture.work().join("workdir/system/bin");
+    let stock = fixture.stock().join("system/bin");
+    let expected = [
+        format!("tmpfs     {}", work.display()),
+        format!(
+            "bind      {} -> {}",
+            modules.join("ma/system/bin/foo").display(),
+            work.join("foo").display()
+        ),
+        format!(
+            "bind      {} -> {}",
+            stock.join("sh").display(),
+            work.join("sh").display()
+        ),
+        format!("move      {} -> {}", work.display(), stock.display()),
+    ];
+    let planned: Vec<_> = ops.iter().filter(|it| expected.contains(it)).collect();
+    // the mirror and the module file go in whatever order the dir lists them
+    assert_eq!(planned.len(), expected.len(), "{ops:#?}");
+    assert_eq!(planned[0], &expected[0]);
+    assert_eq!(planned[3], &expected[3]);
+}
+
+#[test]
+fn whiteout_hides_stock_entry() {
+    let fixture = Fixture::new("whiteout");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("stock/system/bin/ls");
+    fixture.file("module/system/bin/sh");
+
+    let mut root = fixture.collect();
+    let bin = root
+        .children
+        .get_mut("system")
+        .and_then(|it| it.children.get_mut("bin"))
+        .unwrap();
+    bin.children.get_mut("sh").unwrap().file_type = NodeFileType::Whiteout;
+
+    let calls = fixture.mount(&root);
+
+    let work = fixture.work().join("system/bin");
+    assert_eq!(moved(&calls), [fixture.stock().join("system/bin")]);
+    assert!(calls.contains(&Call::CreateFile(work.join("ls"))));
+    assert!(!calls.contains(&Call::CreateFile(work.join("sh"))));
+    assert!(
+        !calls
+            .iter()
+            .any(|call| matches!(call, Call::Bind(_, target) if *target == work.join("sh")))
+    );
+}
+
+#[test]
+fn replace_dir_drops_stock_entries() {
+    let fixture = Fixture::new("replace");
+    fixture.file("stock/system/etc/old.conf");
+    fixture.file("module/system/etc/.replace");
+    fixture.file("module/system/etc/new.conf");
+
+    let calls = fixture.mount(&fixture.collect());
+
+    let work = fixture.work().join("system/etc");
+    assert_eq!(moved(&calls), [fixture.stock().join("system/etc")]);
+    assert!(calls.contains(&Call::Bind(
+        fixture.module().join("system/etc/new.conf"),
+        work.join("new.conf")
+    )));
+    assert!(!calls.contains(&Call::CreateFile(work.join("old.conf"))));
+}
+
+#[test]
+fn root_dir_never_becomes_tmpfs() {
+    let fixture = Fixture::new("root-dir");
+    fixture.file("stock/system/build.prop");
+    fixture.file("module/system/newdir/file");
+
+    let calls = fixture.mount(&fixture.collect());
+
+    assert!(moved(&calls).is_empty());
+    assert!(
+        !calls
+            .iter()
+            .any(|call| matches!(call, Call::Bind(source, _) if source.ends_with("newdir/file")))
+    );
+}
+
+#[test]
+fn journal_rolls_back_newest_first() {
+    let _sigterm = SIGTERM.lock().unwrap();
+    let backend = RecordingBackend::default();
+    let journal = Journal::new(&backend, "/work");
+
+    journal
+        .mount_bind(Path::new("/module/a"), Path::new("/system/a"))
+        .unwrap();
+    journal
+        .mount_bind(Path::new("/work/system/bin"), Path::new("/work/system/bin"))
+        .unwrap();
+    journal
+        .mount_move(Path::new("/work/system/bin"), Path::new("/system/bin"))
+        .unwrap();
+    backend.calls.take();
+    journal.rollback();
+
+    assert_eq!(
+        backend.calls.into_inner(),
+        [
+            Call::Unmount(PathBuf::from("/system/bin")),
+            Call::Unmount(PathBuf::from("/system/a")),
+        ]
+    );
+}
+
+#[test]
+fn conflicts_name_the_last_layered_module_as_winner() {
+    let fixture = Fixture::new("conflicts");
+    fixture.file("modules/a/system/bin/foo");
+    fixture.file("modules/a/system/bin/only_a");
+    fixture.file("modules/b/system/bin/foo");
+    fixture.file("modules/b/system/bin/only_b");
+
+    let modules = fixture.root.join("modules");
+    let mut conflicts = Conflicts::new(&modules);
+    let mut system = Node::new_root("system");
+    for id in ["a", "b"] {
+        system
+            .collect_module_files(
+                modules.join(id).join("system"),
+                &ModuleRules::default(),
+                &FileContexts::default(),
+                &mut conflicts,
+            )
+            .unwrap();
+    }
+
+    let conflicts = conflicts.into_vec();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].path, Path::new("/system/bin/foo"));
+    assert_eq!(conflicts[0].winner, "b");
+    assert_eq!(conflicts[0].modules, ["a", "b"]);
+}
+
+#[test]
+fn modules_are_layered_by_config_then_prop_priority_then_id() {
+    let fixture = Fixture::new("priority");
+    for id in ["a", "b", "c", "d"] {
+        fs::create_dir_all(fixture.module().join(id)).unwrap();
+    }
+    let props = [("a", 0), ("b", 5), ("c", 5), ("d", 0)];
+    let order = |priority: &[&str]| {
+        let mut modules: Vec<_> = fixture
+            .module()
+            .read_dir()
+            .unwrap()
+            .flatten()
+            .map(|entry| {
+                let id = entry.file_name().to_string_lossy().to_string();
+                let prop = props.iter().find(|it| it.0 == id).unwrap().1;
+                (entry, prop)
+            })
+            .collect();
+        let priority: Vec<_> = priority.iter().map(ToString::to_string).collect();
+        sort_by_priority(&mut modules, &priority);
+        modules
+            .iter()
+            .map(|(entry, _)| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // lowest first: a higher prop wins, on a tie the first id
+    assert_eq!(order(&[]), ["d", "a", "c", "b"]);
+    // the config goes above any prop, its first entry highest
+    assert_eq!(order(&["d"]), ["a", "c", "b", "d"]);
+    assert_eq!(order(&["d", "a"]), ["c", "b", "a", "d"]);
+}
+
+#[test]
+fn rules_skip_paths_and_keep_included_ones() {
+    let fixture = Fixture::new("rules");
+    fixture.file("module/system/bin/foo");
+    fixture.file("module/system/bin/bar");
+    fixture.file("module/system/etc/hosts");
+    fixture.file("module/system/lib/libfoo.so");
+
+    let mut rules = ModuleRules {
+        module: fixture.module(),
+        ..ModuleRules::default()
+    };
+    rules.paths.insert("/system/bin".into(), Mode::Skip);
+    rules.paths.insert("/system/bin/bar".into(), Mode::Magic);
+    rules.paths.insert("system/lib/".into(), Mode::Skip);
+
+    let mut system = Node::new_root("system");
+    system
+        .collect_module_files(
+            fixture.module().join("system"),
+            &rules,
+            &FileContexts::default(),
+            &mut Conflicts::new(fixture.root.clone()),
+        )
+        .unwrap();
+
+    let bin = &system.children["bin"];
+    assert!(bin.children.contains_key("bar"));
+    assert!(!bin.children.contains_key("foo"));
+    assert!(system.children["etc"].children.contains_key("hosts"));
+    assert!(!system.children.contains_key("lib"));
+}
+
+#[test]
+fn rules_place_system_vendor_at_the_top_like_file_contexts() {
+    let fixture = Fixture::new("rules-vendor");
+    fixture.file("module/system/vendor/etc/foo.conf");
+    fixture.file("module/system/bin/foo");
+
+    let mut rules = ModuleRules {
+        module: fixture.module(),
+        ..ModuleRules::default()
+    };
+    rules.paths.insert("/vendor".into(), Mode::Skip);
+    let vendor = fixture.module().join("system/vendor");
+    assert!(rules.excludes(&vendor));
+    assert_eq!(rules.mode(&vendor.join("etc/foo.conf")), Mode::Skip);
+    assert_eq!(
+        rules.mode(&fixture.module().join("system/bin/foo")),
+        Mode::Magic
+    );
+
+    // written the way the module lays it out, it still means /vendor
+    let mut rules = ModuleRules {
+        module: fixture.module(),
+        ..ModuleRules::default()
+    };
+    rules.paths.insert("/system/vendor/etc".into(), Mode::Skip);
+    assert_eq!(rules.mode(&vendor.join("etc/foo.conf")), Mode::Skip);
+    assert_eq!(rules.mode(&vendor), Mode::Magic);
+}
+
+#[test]
+fn teardown_skips_mounts_inside_a_tmpfs() {
+    let entry = |target: &str| MountEntry {
+        target: target.into(),
+        kind: MountKind::File,
+        module: None,
+        source: None,
+    };
+    let state = MountState {
+        tempdir: Some("/debug_ramdisk".into()),
+        tmpfs: vec!["/system/bin/sub".into(), "/system/bin".into()],
+        mounts: vec![
+            entry("/system/etc/hosts"),
+            entry("/system/bin/foo"),
+            entry("/system/fonts/a.ttf"),
+        ],
+        ..MountState::default()
+    };
+
+    assert_eq!(
+        state.teardown_order(),
+        [
+            Path::new("/system/fonts/a.ttf"),
+            Path::new("/system/etc/hosts"),
+            Path::new("/system/bin"),
+            Path::new("/debug_ramdisk"),
+        ]
+    );
+}
+
+#[test]
+fn units_start_below_the_partition_roots() {
+    let fixture = Fixture::new("units");
+    fixture.file("modules/a/system/bin/foo");
+    fixture.file("modules/a/system/etc/hosts");
+    fixture.file("modules/b/system/bin/bar");
+
+    let modules = fixture.root.join("modules");
+    let mut system = Node::new_root("system");
+    for id in ["a", "b"] {
+        system
+            .collect_module_files(
+                modules.join(id).join("system"),
+                &ModuleRules::default(),
+                &FileContexts::default(),
+                &mut Conflicts::new(&modules),
+            )
+            .unwrap();
+    }
+    let mut root = Node::new_root("");
+    root.children.insert("system".to_string(), system);
+
+    let units = units(&root, Path::new("/"), &modules);
+    assert_eq!(units.len(), 2);
+    assert_eq!(units[Path::new("/system/bin")], ["a", "b"]);
+    assert_eq!(units[Path::new("/system/etc")], ["a"]);
+}
+
+#[test]
+fn skipped_and_failed_units_are_not_recorded_as_mounted() {
+    let fixture = Fixture::new("units-skipped");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("stock/system/etc/hosts");
+    fixture.file("modules/ma/module.prop");
+    // a new dir right below a partition root cannot get a tmpfs
+    fixture.file("modules/ma/system/newdir/foo");
+    fixture.file("modules/mb/module.prop");
+    fixture.file("modules/mb/system/bin/foo");
+    fixture.file("modules/mb/system/etc/hosts");
+    let modules = fixture.root.join("modules");
+
+    let mut root = collect_module_files(&fixture.stock(), &modules, &[], &[], &mut Vec::new())
+        .unwrap()
+        .unwrap();
+    mark_skipped(&fixture.stock(), &mut root);
+    let system = fixture.stock().join("system");
+    let mounted = |root: &Node| -> Vec<PathBuf> {
+        units(root, &fixture.stock(), &modules)
+            .into_keys()
+            .collect()
+    };
+    assert_eq!(mounted(&root), [system.join("bin"), system.join("etc")]);
+
+    let report = MountReport {
+        failures: vec![ChildFailure {
+            path: system.join("etc"),
+            module: Some("mb".to_string()),
+            error: "bind failed".to_string(),
+        }],
+        ..MountReport::default()
+    };
+    prune(&mut root, &fixture.stock(), &modules, &report);
+    assert_eq!(mounted(&root), [system.join("bin")]);
+    // still in the saved tree, as skipped
+    assert!(root.children["system"].children["etc"].skip);
+}
+
+#[test]
+fn module_tree_closes_on_the_last_module_dir() {
+    let fixture = Fixture::new("module-tree");
+    fixture.file("modules/ma/module.prop");
+    // not a module, and sorted after the only one
+    fixture.file("modules/zz.log");
+
+    let tree = module_tree(&fixture.stock(), &fixture.root.join("modules"), &[], &[]).unwrap();
+
+    assert!(tree.lines().any(|it| it == "└── ma"), "{tree}");
+    assert!(!tree.contains("├──"), "{tree}");
+    assert!(!tree.contains("zz.log"), "{tree}");
+}
+
+#[test]
+fn module_tree_renders_the_tree_it_is_given() {
+    let fixture = Fixture::new("render-tree");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("modules/ma/module.prop");
+    fixture.file("modules/ma/system/bin/sh");
+    let modules = fixture.root.join("modules");
+    let mut root = collect_module_files(&fixture.stock(), &modules, &[], &[], &mut Vec::new())
+        .unwrap()
+        .unwrap();
+    let sh = |tree: &str| {
+        tree.lines()
+            .find(|it| it.contains("sh ["))
+            .unwrap()
+            .to_string()
+    };
+
+    let tree = render_module_tree(&root, &modules).unwrap();
+    assert!(sh(&tree).contains("mounted"), "{tree}");
+
+    // what the run skipped shows as not mounted, nothing is collected again
+    let bin = root
+        .children
+        .get_mut("system")
+        .unwrap()
+        .children
+        .get_mut("bin")
+        .unwrap();
+    bin.children.get_mut("sh").unwrap().skip = true;
+    let tree = render_module_tree(&root, &modules).unwrap();
+    assert!(!sh(&tree).contains("mounted"), "{tree}");
+}
+
+#[test]
+fn partitions_are_placed_by_the_sysroot_layout() {
+    let fixture = Fixture::new("sysroot");
+    fixture.file("stock/vendor/etc/stock.conf");
+    fs::create_dir_all(fixture.stock().join("system")).unwrap();
+    std::os::unix::fs::symlink("../vendor", fixture.stock().join("system/vendor")).unwrap();
+    fixture.file("modules/ma/module.prop");
+    fixture.file("modules/ma/system/vendor/etc/new.conf");
+    fixture.file("modules/ma/system/bin/foo");
+
+    let root = collect_module_files(
+        &fixture.stock(),
+        &fixture.root.join("modules"),
+        &[],
+        &[],
+        &mut Vec::new(),
+    )
+    .unwrap()
+    .unwrap();
+
+    assert!(root.children.contains_key("vendor"));
+    let system = &root.children["system"];
+    assert!(!system.children.contains_key("vendor"));
+    assert!(system.children.contains_key("bin"));
+}
+
+#[test]
+fn aufs_markers_become_whiteouts_and_replace_dirs() {
+    let fixture = Fixture::new("aufs");
+    fixture.file("module/system/bin/.wh.sh");
+    fixture.file("module/system/app/Foo/.wh..wh..opq");
+    fixture.file("module/system/app/Foo/New.apk");
+
+    let root = fixture.collect();
+
+    let system = &root.children["system"];
+    let bin = &system.children["bin"];
+    assert_eq!(bin.children["sh"].file_type, NodeFileType::Whiteout);
+    assert!(!bin.children.contains_key(".wh.sh"));
+    let foo = &system.children["app"].children["Foo"];
+    assert!(foo.replace);
+    assert_eq!(foo.children.keys().collect::<Vec<_>>(), ["New.apk"]);
+}
+
+#[test]
+fn allowed_xattrs_are_copied_onto_recreated_dirs() {
+    let fixture = Fixture::new("xattrs");
+    fixture.file("stock/system/bin/sh");
+    let bin = fixture.stock().join("system/bin");
+    if extattr::lsetxattr(&bin, "user.keep", b"1", extattr::Flags::empty()).is_err() {
+        // the scratch fs has no user xattrs
+        return;
+    }
+    extattr::lsetxattr(&bin, "user.drop", b"2", extattr::Flags::empty()).unwrap();
+
+    let backend = RecordingBackend::default();
+    let policy = XattrPolicy {
+        allow: vec!["user.k*".to_string()],
+        keep_caps: false,
+    };
+    let work = fixture.work().join("system/bin");
+    copy_xattrs(&backend, &policy, &bin, &work);
+
+    assert_eq!(
+        backend.calls.into_inner(),
+        [Call::SetXattr(work, "user.keep".to_string(), b"1".to_vec())]
+    );
+    assert!(!policy.allows("security.selinux"));
+}
+
+#[test]
+fn stock_caps_go_onto_a_clone_and_not_the_module_file() {
+    let fixture = Fixture::new("caps");
+    fixture.file("stock/system/bin/ping");
+    fixture.file("module/system/bin/ping");
+    let stock = fixture.stock().join("system/bin/ping");
+    let module = fixture.module().join("system/bin/ping");
+    // vfs_cap_data v2 with cap_net_raw permitted
+    let caps = [
+        1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    if extattr::lsetxattr(&stock, "security.capability", caps, extattr::Flags::empty()).is_err() {
+        // setting file caps takes CAP_SETFCAP
+        return;
+    }
+
+    let backend = RecordingBackend::default();
+    let policy = XattrPolicy {
+        allow: Vec::new(),
+        keep_caps: true,
+    };
+    let copy = fixture.work().join("system/bin/ping");
+    let source = carry_caps(&backend, &policy, &stock, &module, &copy);
+
+    let calls = backend.calls.into_inner();
+    assert_eq!(source, copy);
+    assert!(calls.contains(&Call::CopyFile(module.clone(), copy.clone())));
+    assert!(calls.contains(&Call::SetXattr(
+        copy,
+        "security.capability".to_string(),
+        caps.to_vec()
+    )));
+    assert!(
+        !calls
+            .iter()
+            .any(|call| matches!(call, Call::SetXattr(path, ..) if *path == module))
+    );
+
+    // without keep_caps the module file is mounted as it is
+    let source = carry_caps(
+        &RecordingBackend::default(),
+        &XattrPolicy::default(),
+        &stock,
+        &module,
+        &fixture.work().join("system/bin/ping"),
+    );
+    assert_eq!(source, module);
+}
+
+#[test]
+fn file_contexts_label_module_files_and_new_dirs() {
+    let fixture = Fixture::new("contexts");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("stock/system/etc/hosts");
+    fixture.file("module/system/bin/foo");
+    fixture.file("module/system/etc/new/bar");
+    fs::write(
+        fixture.module().join("file_contexts"),
+        "# labels\n\
+         /system/bin/.*        u:object_r:system_file:s0\n\
+         /system/bin/foo       u:object_r:foo_exec:s0\n\
+         /system/etc/new   -d  u:object_r:new_dir:s0\n\
+         /system/etc/broken    not_a_context\n",
+    )
+    .unwrap();
+
+    let root = fixture.collect();
+    let system = &root.children["system"];
+    assert_eq!(
+        system.children["bin"].children["foo"].context.as_deref(),
+        Some("u:object_r:foo_exec:s0")
+    );
+    assert_eq!(
+        system.children["etc"].children["new"].context.as_deref(),
+        Some("u:object_r:new_dir:s0")
+    );
+    // the type filter keeps the dir label off the file below it
+    assert_eq!(
+        system.children["etc"].children["new"].children["bar"].context,
+        None
+    );
+
+    let calls = fixture.mount(&root);
+    assert!(calls.contains(&Call::SetFilecon(
+        fixture.module().join("system/bin/foo"),
+        "u:object_r:foo_exec:s0".to_string()
+    )));
+    assert!(calls.contains(&Call::SetFilecon(
+        fixture.work().join("system/etc/new"),
+        "u:object_r:new_dir:s0".to_string()
+    )));
+}
+
+#[test]
+fn fifos_are_bound_instead_of_removing_the_target() {
+    let fixture = Fixture::new("fifo");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("module/system/bin/foo");
+    for fifo in ["stock/system/bin/pipe", "module/system/bin/newpipe"] {
+        let path = CString::new(fixture.root.join(fifo).into_os_string().into_vec()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+    }
+
+    let root = fixture.collect();
+    let bin = &root.children["system"].children["bin"];
+    assert_eq!(bin.children["newpipe"].file_type, NodeFileType::Fifo);
+
+    let calls = fixture.mount(&root);
+    let work = fixture.work().join("system/bin");
+    assert!(calls.contains(&Call::Bind(
+        fixture.module().join("system/bin/newpipe"),
+        work.join("newpipe")
+    )));
+    // the stock one is mirrored into the new tmpfs
+    assert!(calls.contains(&Call::Bind(
+        fixture.stock().join("system/bin/pipe"),
+        work.join("pipe")
+    )));
+}
+
+#[test]
+fn recovery_mounts_a_subtree_again_without_the_failing_module() {
+    let _sigterm = SIGTERM.lock().unwrap();
+    let fixture = Fixture::new("recovery");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("mod_a/system/bin/foo");
+    fixture.file("mod_b/system/bin/bar");
+    let root = fixture.collect_modules(&["mod_a", "mod_b"]);
+
+    let backend = RecordingBackend {
+        fail_bind: Some(fixture.root.join("mod_b/system/bin/bar")),
+        ..RecordingBackend::default()
+    };
+    MagicMount::new(
+        &root,
+        fixture.stock(),
+        fixture.work(),
+        false,
+        &backend,
+        &Recorder::default(),
+    )
+    .with_recovery(&fixture.root)
+    .do_mount()
+    .unwrap();
+    let calls = backend.calls.into_inner();
+
+    // only the second attempt got moved over the stock dir
+    let retry = fixture.work().join("system/.retry1/bin");
+    assert_eq!(moved(&calls), [fixture.stock().join("system/bin")]);
+    assert!(calls.contains(&Call::Move(
+        retry.clone(),
+        fixture.stock().join("system/bin")
+    )));
+    assert!(calls.contains(&Call::Bind(
+        fixture.root.join("mod_a/system/bin/foo"),
+        retry.join("foo")
+    )));
+    assert!(!calls.contains(&Call::CreateFile(retry.join("bar"))));
+
+    // what is left of a dir the dropped module owned goes to the others
+    let mut bin = root.children["system"].children["bin"].clone();
+    bin.module_path = Some(fixture.root.join("mod_b/system/bin"));
+    assert!(bin.drop_module(&fixture.root.join("mod_b")));
+    assert_eq!(bin.module_path, Some(fixture.root.join("mod_a/system/bin")));
+    assert!(!bin.drop_module(&fixture.root.join("mod_a")));
+}
+
+#[test]
+fn sigterm_mid_mount_blames_no_module() {
+    let _sigterm = SIGTERM.lock().unwrap();
+    let fixture = Fixture::new("sigterm");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("mod_a/system/bin/foo");
+    fixture.file("mod_b/system/bin/bar");
+    let root = fixture.collect_modules(&["mod_a", "mod_b"]);
+
+    let backend = RecordingBackend {
+        sigterm_bind: Some(fixture.root.join("mod_b/system/bin/bar")),
+        ..RecordingBackend::default()
+    };
+    catch_sigterm();
+    let ret = MagicMount::new(
+        &root,
+        fixture.stock(),
+        fixture.work(),
+        false,
+        &backend,
+        &Recorder::default(),
+    )
+    .with_recovery(&fixture.root)
+    .do_mount();
+    reset_terminated();
+    let calls = backend.calls.into_inner();
+
+    // the run ends with the shutdown instead of mounting bin again
+    let error = ret.unwrap_err();
+    assert!(format!("{error:#}").contains("SIGTERM"), "{error:#}");
+    assert!(moved(&calls).is_empty());
+    assert!(!calls.iter().any(|call| matches!(
+        call,
+        Call::CreateDir(path) if path.starts_with(fixture.work().join("system/.retry1"))
+    )));
+}
+
+#[test]
+fn runs_keep_their_own_report() {
+    let fixture = Fixture::new("recorders");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("stock/system/etc/hosts");
+    fixture.file("module/system/bin/sh");
+    let first = Recorder::default();
+    fixture.mount_into(&fixture.collect(), &first);
+
+    fixture.file("module/system/etc/hosts");
+    let second = Recorder::default();
+    fixture.mount_into(&fixture.collect(), &second);
+
+    let report = |recorder: &Recorder| {
+        recorder
+            .report(&fixture.stock(), &fixture.root, &Ok(()))
+            .total
+            .binds
+    };
+    assert_eq!((report(&first), report(&second)), (1, 2));
+}
+
+#[test]
+fn a_dry_run_leaves_the_mounts_of_a_real_one_alone() {
+    let fixture = Fixture::new("dry-run");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("module/module.prop");
+    fixture.file("module/system/bin/sh");
+    let root = fixture.collect();
+    let recorder = Recorder::default();
+    fixture.mount_into(&root, &recorder);
+
+    let ops = crate::magic_mount::plan(fixture.work(), &fixture.stock(), &fixture.root, &[], &[])
+        .unwrap();
+    assert!(!ops.is_empty());
+
+    let state = MountState::collect(
+        &fixture.work(),
+        &fixture.stock(),
+        &fixture.root,
+        &root,
+        &recorder,
+        &Ok(()),
+    );
+    let mounted: Vec<_> = state.mounts.iter().map(|it| &it.target).collect();
+    assert_eq!(mounted, [&fixture.stock().join("system/bin/sh")]);
+    assert_eq!(state.modules, ["module"]);
+}
+
+#[test]
+fn sync_reports_the_synced_units_next_to_the_untouched_ones() {
+    let modules = PathBuf::from("/data/adb/modules");
+    let (bin, etc) = (Path::new("/system/bin"), Path::new("/system/etc"));
+    let boot = Recorder::default();
+    boot.record(
+        Event::Bind,
+        &bin.join("foo"),
+        Some(&modules.join("ma/system/bin/foo")),
+    );
+    boot.record(
+        Event::Bind,
+        &etc.join("hosts"),
+        Some(&modules.join("ma/system/etc/hosts")),
+    );
+    let mut state = MountState::collect(
+        Path::new("/debug_ramdisk"),
+        Path::new("/"),
+        &modules,
+        &Node::new_root(""),
+        &boot,
+        &Ok(()),
+    );
+    let failure = |path: &Path| ChildFailure {
+        path: path.to_path_buf(),
+        module: Some("ma".to_string()),
+        error: "boom".to_string(),
+    };
+    let previous = MountReport {
+        failures: vec![failure(&bin.join("bar")), failure(&etc.join("baz"))],
+        ..MountReport::default()
+    };
+
+    // etc is remounted with a symlink of another module
+    state.forget(etc);
+    let synced = Recorder::default();
+    synced.record(
+        Event::Symlink,
+        &etc.join("hosts"),
+        Some(&modules.join("mb/system/etc/hosts")),
+    );
+    state.absorb(etc, vec!["mb".to_string()], &modules, &synced);
+    let changed = std::collections::BTreeSet::from([etc.to_path_buf()]);
+
+    let report = synced_report(
+        &state,
+        &previous,
+        &changed,
+        Vec::new(),
+        Path::new("/"),
+        &modules,
+    );
+    assert_eq!((report.total.binds, report.total.symlinks), (1, 1));
+    assert_eq!(report.modules["mb"].symlinks, 1);
+    assert_eq!(report.failures, [failure(&bin.join("bar"))]);
+}
+
+#[test]
+fn report_counts_per_module_and_partition() {
+    let modules = PathBuf::from("/data/adb/modules");
+    let events = [
+        (
+            Event::Bind,
+            PathBuf::from("/system/bin/foo"),
+            Some(modules.join("mod_a/system/bin/foo")),
+        ),
+        (
+            Event::Tmpfs,
+            PathBuf::from("/system/bin"),
+            Some(modules.join("mod_a/system/bin")),
+        ),
+        (Event::Mirror, PathBuf::from("/system/bin/sh"), None),
+        (
+            Event::Symlink,
+            PathBuf::from("/vendor/bin/bar"),
+            Some(modules.join("mod_b/system/vendor/bin/bar")),
+        ),
+    ];
+    let failures = vec![(
+        PathBuf::from("/vendor/etc/baz"),
+        Some(modules.join("mod_b/system/vendor/etc/baz")),
+        "boom".to_string(),
+    )];
+
+    let report = MountReport::build(&events, failures, Path::new("/"), &modules, &Ok(()));
+
+    assert!(report.success);
+    assert_eq!((report.total.binds, report.total.mirrors), (1, 1));
+    assert_eq!(report.modules["mod_a"].tmpfs, 1);
+    assert_eq!(report.modules["mod_b"].symlinks, 1);
+    assert_eq!(report.partitions["system"].mirrors, 1);
+    assert_eq!(report.partitions["vendor"].symlinks, 1);
+    assert_eq!(report.failures[0].module.as_deref(), Some("mod_b"));
+}
+
+#[test]
+fn modules_are_quarantined_after_failing_runs_in_a_row() {
+    let failing = |ids: &[&str]| {
+        ids.iter()
+            .map(|it| ((*it).to_string(), format!("{it} broke")))
+            .collect()
+    };
+    let mut strikes = std::collections::BTreeMap::new();
+
+    assert!(strike(&mut strikes, failing(&["mod_a", "mod_b"]), 2).is_empty());
+    let out = strike(&mut strikes, failing(&["mod_a"]), 2);
+    assert_eq!(out[0].0, "mod_a");
+    // mod_b got through this run, so its strike is gone
+    assert!(strikes.is_empty());
+
+    strike(&mut strikes, failing(&["mod_b"]), 2);
+    let out = strike(&mut strikes, failing(&["mod_b"]), 2);
+    assert_eq!(
+        out,
+        [(
+            "mod_b".to_string(),
+            Strike {
+                failures: 2,
+                reason: "mod_b broke".to_string()
+            }
+        )]
+    );
+}
+
+#[test]
+fn a_failed_run_blames_the_module_it_broke_on() {
+    let _sigterm = SIGTERM.lock().unwrap();
+    let fixture = Fixture::new("blamed");
+    fixture.file("stock/system/bin/sh");
+    fixture.file("mod_a/system/bin/foo");
+    fixture.file("mod_b/system/bin/bar");
+    let root = fixture.collect_modules(&["mod_a", "mod_b"]);
+
+    let backend = RecordingBackend {
+        fail_bind: Some(fixture.root.join("mod_b/system/bin/bar")),
+        ..RecordingBackend::default()
+    };
+    // a tmpfs dir fails as a whole, with nothing above it to recover
+    let result = MagicMount::new(
+        &root.children["system"].children["bin"],
+        fixture.stock().join("system"),
+        fixture.work().join("system"),
+        false,
+        &backend,
+        &Recorder::default(),
+    )
+    .do_mount()
+    .map(|()| MountReport::default());
+
+    assert!(result.is_err());
+    let failing = blamed(&fixture.root, &result);
+    assert_eq!(failing.keys().collect::<Vec<_>>(), ["mod_b"]);
+}
+
+#[test]
+fn boot_guard_falls_back_to_known_good_then_skips() {
+    let fixture = Fixture::new("guard");
+    let guard = BootGuard::new(fixture.root.join("boot_count"), 2);
+
+    assert_eq!(guard.enter(true), BootVerdict::All);
+    // boot-completed clears the streak
+    guard.completed().unwrap();
+    assert_eq!(guard.count(), 0);
+
+    assert_eq!(guard.enter(true), BootVerdict::All);
+    assert_eq!(guard.enter(true), BootVerdict::All);
+    assert!(matches!(guard.enter(true), BootVerdict::KnownGood(_)));
+    assert!(matches!(guard.enter(true), BootVerdict::KnownGood(_)));
+    assert!(matches!(guard.enter(true), BootVerdict::Skip(_)));
+    guard.completed().unwrap();
+    assert_eq!(guard.enter(true), BootVerdict::All);
+
+    // without a snapshot there is nothing softer than skipping
+    let fresh = BootGuard::new(fixture.root.join("fresh_count"), 1);
+    assert_eq!(fresh.enter(false), BootVerdict::All);
+    assert!(matches!(fresh.enter(false), BootVerdict::Skip(_)));
+
+    let off = BootGuard::new(fixture.root.join("off_count"), 0);
+    for _ in 0..5 {
+        assert_eq!(off.enter(false), BootVerdict::All);
+    }
+}
+
+#[test]
+fn known_good_fingerprint_follows_module_props() {
+    let fixture = Fixture::new("fingerprint");
+    fs::write(
+        fixture.module().join("module.prop"),
+        "id=module\nversion=1\n",
+    )
+    .unwrap();
+    let modules = ["module".to_string()];
+    let root = &fixture.root;
+
+    let before = fingerprint(root, &modules);
+    assert_eq!(before, fingerprint(root, &modules));
+    fs::write(
+        fixture.module().join("module.prop"),
+        "id=module\nversion=2\n",
+    )
+    .unwrap();
+    assert_ne!(before, fingerprint(root, &modules));
+    assert_ne!(fingerprint(root, &[]), fingerprint(root, &modules));
+}
+
+#[test]
+fn known_good_leaves_out_only_the_modules_that_changed() {
+    let fixture = Fixture::new("changed");
+    let root = &fixture.root;
+    for id in ["mod_a", "mod_b"] {
+        fs::create_dir_all(root.join(id)).unwrap();
+        fs::write(root.join(id).join("module.prop"), format!("id={id}\n")).unwrap();
+    }
+    let modules = vec!["mod_a".to_string(), "mod_b".to_string()];
+    let mut known_good = KnownGood {
+        time: 0,
+        fingerprint: fingerprint(root, &modules),
+        fingerprints: modules
+            .iter()
+            .map(|id| (id.clone(), fingerprint(root, std::slice::from_ref(id))))
+            .collect(),
+        modules,
+        tree: None,
+    };
+    assert!(known_good.changed(root).is_empty());
+
+    fs::write(root.join("mod_b/module.prop"), "id=mod_b\nversion=2\n").unwrap();
+    assert_eq!(known_good.changed(root), ["mod_b"]);
+
+    // older snapshots only have the fingerprint of all of them
+    known_good.fingerprints.clear();
+    assert_eq!(known_good.changed(root), ["mod_a", "mod_b"]);
+}
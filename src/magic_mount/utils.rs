@@ -2,24 +2,45 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
+    cmp::Reverse,
     collections::HashSet,
-    fs::{self, DirEntry, Metadata, create_dir, create_dir_all, read_link},
-    os::unix::fs::{MetadataExt, symlink},
+    fs::{self, DirEntry, Metadata, read_link},
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
 };
 
 use anyhow::{Result, bail};
-use rustix::{
-    fs::{Gid, Mode, Uid, chmod, chown},
-    mount::mount_bind,
-};
 
 use crate::{
-    defs::{DISABLE_FILE_NAME, REMOVE_FILE_NAME, SKIP_MOUNT_FILE_NAME},
-    magic_mount::node::Node,
-    utils::{lgetfilecon, lsetfilecon, validate_module_id},
+    defs::{DISABLE_FILE_NAME, REMOVE_FILE_NAME, SELINUX_ENFORCE, SKIP_MOUNT_FILE_NAME},
+    magic_mount::{
+        backend::MountBackend,
+        conflict::{Conflict, Conflicts},
+        contexts::FileContexts,
+        known_good::allowed,
+        node::Node,
+        quarantine::is_quarantined,
+        rules::ModuleRules,
+        xattr::{copy_xattrs, policy},
+    },
+    utils::validate_module_id,
 };
 
+// partitions that sit at the top of the device even when a module ships
+// them below system
+const SPLIT_PARTITIONS: [&str; 4] = ["vendor", "system_ext", "product", "odm"];
+
+/// Maps `<partition>/...` inside a module to the path it ends up at on the
+/// device, with `system/vendor` and the like at the top.
+pub fn device_path(relative: &Path) -> PathBuf {
+    let relative = relative
+        .strip_prefix("system")
+        .ok()
+        .filter(|it| SPLIT_PARTITIONS.iter().any(|p| it.starts_with(p)))
+        .unwrap_or(relative);
+    Path::new("/").join(relative)
+}
+
 fn metadata_path<P>(path: P, node: &Node) -> Result<(Metadata, PathBuf)>
 where
     P: AsRef<Path>,
@@ -34,8 +55,44 @@ where
     }
 }
 
-pub fn tmpfs_skeleton<P>(path: P, work_dir_path: P, node: &Node) -> Result<()>
+/// Gives `dst` the selinux context of `src`. Without selinux there is none to
+/// copy, which only happens when populating a fixture or image on a host.
+fn copy_filecon<B>(backend: &B, src: &Path, dst: &Path) -> Result<()>
+where
+    B: MountBackend,
+{
+    match backend.get_filecon(src) {
+        Ok(con) => backend.set_filecon(dst, &con),
+        Err(e) if !Path::new(SELINUX_ENFORCE).exists() => {
+            log::debug!("no selinux, not copying context: {e:#}");
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Gives `path` the label the module's `file_contexts` assigned to `node`,
+/// returns whether it did. A label that cannot be set is reported and the
+/// node keeps the one it has.
+pub fn apply_context<B>(backend: &B, node: &Node, path: &Path) -> bool
 where
+    B: MountBackend,
+{
+    let Some(con) = &node.context else {
+        return false;
+    };
+    match backend.set_filecon(path, con) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("failed to label {} as {con}: {e:#}", path.display());
+            false
+        }
+    }
+}
+
+pub fn tmpfs_skeleton<B, P>(backend: &B, path: P, work_dir_path: P, node: &Node) -> Result<()>
+where
+    B: MountBackend,
     P: AsRef<Path>,
 {
     let (path, work_dir_path) = (path.as_ref(), work_dir_path.as_ref());
@@ -45,54 +102,52 @@ where
         work_dir_path.display()
     );
 
-    create_dir_all(work_dir_path)?;
+    backend.create_dir_all(work_dir_path)?;
 
     let (metadata, path) = metadata_path(path, node)?;
 
-    chmod(work_dir_path, Mode::from_raw_mode(metadata.mode()))?;
-    chown(
-        work_dir_path,
-        Some(Uid::from_raw(metadata.uid())),
-        Some(Gid::from_raw(metadata.gid())),
-    )?;
-    lsetfilecon(work_dir_path, lgetfilecon(path)?.as_str())?;
+    backend.chmod(work_dir_path, metadata.mode())?;
+    backend.chown(work_dir_path, metadata.uid(), metadata.gid())?;
+    if !apply_context(backend, node, work_dir_path) {
+        copy_filecon(backend, &path, work_dir_path)?;
+    }
+    copy_xattrs(backend, policy(), &path, work_dir_path);
 
     Ok(())
 }
 
-pub fn mount_mirror<P>(path: P, work_dir_path: P, entry: &DirEntry) -> Result<()>
+pub fn mount_mirror<B, P>(backend: &B, path: P, work_dir_path: P, entry: &DirEntry) -> Result<()>
 where
+    B: MountBackend,
     P: AsRef<Path>,
 {
     let path = path.as_ref().join(entry.file_name());
     let work_dir_path = work_dir_path.as_ref().join(entry.file_name());
     let file_type = entry.file_type()?;
 
-    if file_type.is_file() {
+    // a bind mount keeps fifos and sockets connected to the stock ones
+    if file_type.is_file() || file_type.is_fifo() || file_type.is_socket() {
         log::debug!(
             "mount mirror file {} -> {}",
             path.display(),
             work_dir_path.display()
         );
-        fs::File::create(&work_dir_path)?;
-        mount_bind(&path, &work_dir_path)?;
+        backend.create_file(&work_dir_path)?;
+        backend.mount_bind(&path, &work_dir_path)?;
     } else if file_type.is_dir() {
         log::debug!(
             "mount mirror dir {} -> {}",
             path.display(),
             work_dir_path.display()
         );
-        create_dir(&work_dir_path)?;
+        backend.create_dir(&work_dir_path)?;
         let metadata = entry.metadata()?;
-        chmod(&work_dir_path, Mode::from_raw_mode(metadata.mode()))?;
-        chown(
-            &work_dir_path,
-            Some(Uid::from_raw(metadata.uid())),
-            Some(Gid::from_raw(metadata.gid())),
-        )?;
-        lsetfilecon(&work_dir_path, lgetfilecon(&path)?.as_str())?;
+        backend.chmod(&work_dir_path, metadata.mode())?;
+        backend.chown(&work_dir_path, metadata.uid(), metadata.gid())?;
+        copy_filecon(backend, &path, &work_dir_path)?;
+        copy_xattrs(backend, policy(), &path, &work_dir_path);
         for entry in path.read_dir()?.flatten() {
-            mount_mirror(&path, &work_dir_path, &entry)?;
+            mount_mirror(backend, &path, &work_dir_path, &entry)?;
         }
     } else if file_type.is_symlink() {
         log::debug!(
@@ -100,22 +155,49 @@ where
             path.display(),
             work_dir_path.display()
         );
-        clone_symlink(&path, &work_dir_path)?;
+        clone_symlink(backend, &path, &work_dir_path)?;
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        log::debug!(
+            "create mirror device {} -> {}",
+            path.display(),
+            work_dir_path.display()
+        );
+        clone_device(backend, &path, &work_dir_path)?;
     }
 
     Ok(())
 }
 
-pub fn collect_module_files(
-    module_dir: &Path,
-    extra_partitions: &[String],
-) -> Result<Option<Node>> {
-    let mut root = Node::new_root("");
-    let mut system = Node::new_root("system");
-    let module_root = module_dir;
-    let mut has_file = HashSet::new();
+/// `priority=` from module.prop, higher values override lower ones.
+fn prop_priority(prop: &str) -> i32 {
+    prop.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "priority")
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or_default()
+}
 
-    log::debug!("begin collect module files: {}", module_root.display());
+/// Orders modules from the lowest priority to the highest, so later ones win.
+/// Modules named in `priority` (highest first) go above all others, the rest
+/// follow their module.prop `priority=`, then the first id alphabetically wins.
+pub fn sort_by_priority(modules: &mut [(DirEntry, i32)], priority: &[String]) {
+    modules.sort_by_cached_key(|(entry, prop)| {
+        let id = entry.file_name().to_string_lossy().to_string();
+        let rank = priority
+            .iter()
+            .position(|it| *it == id)
+            .map_or(0, |index| priority.len() - index);
+        (rank, *prop, Reverse(id))
+    });
+}
+
+/// Modules that are enabled and modify one of `partitions`, with their
+/// module.prop priority.
+fn enabled_modules(
+    module_root: &Path,
+    partitions: &HashSet<String>,
+) -> Result<Vec<(DirEntry, i32)>> {
+    let mut modules = Vec::new();
 
     for entry in module_root.read_dir()?.flatten() {
         if !entry.file_type()?.is_dir() {
@@ -146,13 +228,18 @@ pub fn collect_module_files(
             log::debug!("skipped module {id}, due to disable/remove/skip_mount");
             continue;
         }
+        if is_quarantined(&entry.path()) {
+            log::warn!("skipped module {id}, it is quarantined");
+            continue;
+        }
+        if !allowed(&id) {
+            log::warn!("skipped module {id}, it is not known good");
+            continue;
+        }
 
         let mut modified = false;
-        let mut partitions = HashSet::new();
-        partitions.insert("system".to_string());
-        partitions.extend(extra_partitions.iter().cloned());
 
-        for p in &partitions {
+        for p in partitions {
             if entry.path().join(p).is_dir() {
                 modified = true;
                 break;
@@ -164,17 +251,60 @@ pub fn collect_module_files(
             continue;
         }
 
+        modules.push((entry, prop_priority(&string)));
+    }
+
+    Ok(modules)
+}
+
+/// Collects the modules into one tree, with partitions placed the way they
+/// are laid out under `sysroot`.
+pub fn collect_module_files(
+    sysroot: &Path,
+    module_dir: &Path,
+    extra_partitions: &[String],
+    priority: &[String],
+    conflicts: &mut Vec<Conflict>,
+) -> Result<Option<Node>> {
+    let mut root = Node::new_root("");
+    let mut system = Node::new_root("system");
+    let module_root = module_dir;
+    let mut has_file = HashSet::new();
+    let mut claims = Conflicts::new(module_root);
+
+    let mut partitions = HashSet::new();
+    partitions.insert("system".to_string());
+    partitions.extend(extra_partitions.iter().cloned());
+
+    log::debug!("begin collect module files: {}", module_root.display());
+
+    let mut modules = enabled_modules(module_root, &partitions)?;
+    sort_by_priority(&mut modules, priority);
+
+    for (entry, _) in modules {
         log::debug!("collecting {}", entry.path().display());
+        let rules = ModuleRules::load_or_default(entry.path());
+        let contexts = FileContexts::load_or_default(entry.path());
 
-        for p in partitions {
-            if !entry.path().join(&p).exists() {
+        for p in &partitions {
+            let path = entry.path().join(p);
+            if !path.exists() {
+                continue;
+            }
+            if rules.excludes(&path) {
+                log::debug!("skipped {} by rules", path.display());
                 continue;
             }
 
-            has_file.insert(system.collect_module_files(entry.path().join(&p))?);
+            has_file.insert(system.collect_module_files(path, &rules, &contexts, &mut claims)?);
         }
     }
 
+    *conflicts = claims.into_vec();
+    for conflict in conflicts.iter() {
+        log::warn!("conflict: {conflict}");
+    }
+
     if has_file.contains(&true) {
         const BUILTIN_PARTITIONS: [(&str, bool); 4] = [
             ("vendor", true),
@@ -184,8 +314,8 @@ pub fn collect_module_files(
         ];
 
         for (partition, require_symlink) in BUILTIN_PARTITIONS {
-            let path_of_root = Path::new("/").join(partition);
-            let path_of_system = Path::new("/system").join(partition);
+            let path_of_root = sysroot.join(partition);
+            let path_of_system = sysroot.join("system").join(partition);
             if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
                 let name = partition.to_string();
                 if let Some(node) = system.children.remove(&name) {
@@ -202,8 +332,8 @@ pub fn collect_module_files(
                 continue;
             }
 
-            let path_of_root = Path::new("/").join(partition);
-            let path_of_system = Path::new("/system").join(partition);
+            let path_of_root = sysroot.join(partition);
+            let path_of_system = sysroot.join("system").join(partition);
             let require_symlink = false;
 
             if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
@@ -222,13 +352,15 @@ pub fn collect_module_files(
     }
 }
 
-pub fn clone_symlink<S>(src: S, dst: S) -> Result<()>
+pub fn clone_symlink<B, S>(backend: &B, src: S, dst: S) -> Result<()>
 where
+    B: MountBackend,
     S: AsRef<Path>,
 {
     let src_symlink = read_link(src.as_ref())?;
-    symlink(&src_symlink, dst.as_ref())?;
-    lsetfilecon(dst.as_ref(), lgetfilecon(src.as_ref())?.as_str())?;
+    backend.symlink(&src_symlink, dst.as_ref())?;
+    copy_filecon(backend, src.as_ref(), dst.as_ref())?;
+    copy_xattrs(backend, policy(), src.as_ref(), dst.as_ref());
     log::debug!(
         "clone symlink {} -> {}({})",
         dst.as_ref().display(),
@@ -237,3 +369,36 @@ where
     );
     Ok(())
 }
+
+/// Copies the regular file `src` to `dst` with its mode, owner and label.
+pub fn clone_file<B>(backend: &B, src: &Path, dst: &Path) -> Result<()>
+where
+    B: MountBackend,
+{
+    let metadata = src.metadata()?;
+    backend.copy_file(src, dst)?;
+    backend.chmod(dst, metadata.mode())?;
+    backend.chown(dst, metadata.uid(), metadata.gid())?;
+    copy_filecon(backend, src, dst)?;
+    copy_xattrs(backend, policy(), src, dst);
+    log::debug!("clone file {} -> {}", src.display(), dst.display());
+    Ok(())
+}
+
+/// Recreates the device node `src` at `dst` with its mode, owner and label.
+pub fn clone_device<B, S>(backend: &B, src: S, dst: S) -> Result<()>
+where
+    B: MountBackend,
+    S: AsRef<Path>,
+{
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let metadata = src.symlink_metadata()?;
+    backend.mknod(dst, metadata.mode(), metadata.rdev())?;
+    // mknod applies the umask
+    backend.chmod(dst, metadata.mode())?;
+    backend.chown(dst, metadata.uid(), metadata.gid())?;
+    copy_filecon(backend, src, dst)?;
+    copy_xattrs(backend, policy(), src, dst);
+    log::debug!("clone device {} -> {}", src.display(), dst.display());
+    Ok(())
+}
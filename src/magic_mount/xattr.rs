@@ -0,0 +1,113 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::{
+    defs::{CAPABILITY_XATTR, SELINUX_XATTR},
+    magic_mount::{backend::MountBackend, utils::clone_file},
+};
+
+static POLICY: OnceLock<XattrPolicy> = OnceLock::new();
+
+/// Which xattrs besides the selinux context survive when a node is recreated.
+#[derive(Debug, Default)]
+pub struct XattrPolicy {
+    /// names to copy, a trailing `*` matches any suffix
+    pub allow: Vec<String>,
+    /// give module files the capabilities of the stock file they replace
+    pub keep_caps: bool,
+}
+
+impl XattrPolicy {
+    pub fn allows(&self, name: &str) -> bool {
+        // the context is copied on its own, it must never be skipped
+        name != SELINUX_XATTR
+            && self.allow.iter().any(|it| {
+                it.strip_suffix('*')
+                    .map_or_else(|| it == name, |prefix| name.starts_with(prefix))
+            })
+    }
+}
+
+/// Sets the policy for this run, before anything is mounted.
+pub fn configure_xattrs(allow: &[String], keep_caps: bool) {
+    let _ = POLICY.set(XattrPolicy {
+        allow: allow.to_vec(),
+        keep_caps,
+    });
+}
+
+pub fn policy() -> &'static XattrPolicy {
+    POLICY.get_or_init(XattrPolicy::default)
+}
+
+/// Copies the xattrs of `src` that `policy` allows onto `dst`. Missing ones
+/// only cost a warning, the node is usable without them.
+pub fn copy_xattrs<B>(backend: &B, policy: &XattrPolicy, src: &Path, dst: &Path)
+where
+    B: MountBackend,
+{
+    if policy.allow.is_empty() {
+        return;
+    }
+
+    let names = match backend.list_xattrs(src) {
+        Ok(names) => names,
+        Err(e) => {
+            log::debug!("cannot list xattrs of {}: {e:#}", src.display());
+            return;
+        }
+    };
+    for name in names.iter().filter(|it| policy.allows(it)) {
+        if let Err(e) = backend
+            .get_xattr(src, name)
+            .and_then(|value| backend.set_xattr(dst, name, &value))
+        {
+            log::warn!("failed to copy {name} to {}: {e:#}", dst.display());
+        }
+    }
+}
+
+/// The file to mount over the stock file `stock` in place of the module file
+/// `module_path`. If the stock file has capabilities the module file lacks,
+/// the module file is cloned to `copy` in the work dir and the clone gets
+/// them, so the module stays as it shipped and an OTA is followed next boot.
+pub fn carry_caps<B>(
+    backend: &B,
+    policy: &XattrPolicy,
+    stock: &Path,
+    module_path: &Path,
+    copy: &Path,
+) -> PathBuf
+where
+    B: MountBackend,
+{
+    if !policy.keep_caps || backend.get_xattr(module_path, CAPABILITY_XATTR).is_ok() {
+        return module_path.to_path_buf();
+    }
+    let Ok(caps) = backend.get_xattr(stock, CAPABILITY_XATTR) else {
+        return module_path.to_path_buf();
+    };
+
+    log::debug!(
+        "carry capabilities {} -> {}",
+        stock.display(),
+        copy.display()
+    );
+    let ret = copy
+        .parent()
+        .map_or(Ok(()), |it| backend.create_dir_all(it))
+        .and_then(|()| clone_file(backend, module_path, copy))
+        .and_then(|()| backend.set_xattr(copy, CAPABILITY_XATTR, &caps));
+    match ret {
+        Ok(()) => copy.to_path_buf(),
+        Err(e) => {
+            log::warn!("failed to carry capabilities to {}: {e:#}", copy.display());
+            module_path.to_path_buf()
+        }
+    }
+}
@@ -3,13 +3,17 @@
 #![deny(clippy::all, clippy::pedantic)]
 #![warn(clippy::nursery)]
 
+mod cli;
 mod config;
 mod defs;
 mod magic_mount;
 mod scanner;
 mod utils;
 
-use std::{io::Write, path::PathBuf};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
 use env_logger::Builder;
@@ -19,7 +23,7 @@ use rustix::{
     path::Arg,
 };
 
-use crate::config::Config;
+use crate::{config::Config, defs::BOOT_COUNT_FILE, magic_mount::BootVerdict};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -47,39 +51,74 @@ fn init_logger(verbose: bool) {
     log::info!("log level: {}", level.as_str());
 }
 
-fn main() -> Result<()> {
-    let config = Config::load()?;
-
-    let args: Vec<_> = std::env::args().collect();
+/// Counts this boot, returns `false` if nothing may be mounted. After failed
+/// boots the run is limited to the last known good modules that did not
+/// change since, none left means nothing is mounted.
+fn boot_guard(config: &Config) -> bool {
+    let known_good = magic_mount::KnownGood::load().ok();
+    let guard = magic_mount::BootGuard::new(
+        magic_mount::state_file(BOOT_COUNT_FILE),
+        config.bootloop_threshold,
+    );
 
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "scan" => {
-                let modules = scanner::scan_modules(&config.moduledir, &config.partitions);
-
-                if let Some(s) = args.get(2)
-                    && s.as_str() == "--json"
-                {
-                    let json = serde_json::to_string(&modules)?;
-                    println!("{json}");
-                } else {
-                    for module in modules {
-                        println!("{}", module.id);
-                    }
-                }
-                return Ok(());
+    match guard.enter(known_good.is_some()) {
+        BootVerdict::All => true,
+        BootVerdict::KnownGood(reason) => {
+            log::warn!("{reason}");
+            let Some(known_good) = known_good else {
+                return true;
+            };
+            let changed = known_good.changed(&config.moduledir);
+            if !changed.is_empty() && changed.len() == known_good.modules.len() {
+                let reason =
+                    "all known good modules changed since they were recorded, skipping all modules";
+                log::error!("{reason}");
+                magic_mount::skip_mount(reason);
+                return false;
             }
-            "version" => {
-                println!("{{ \"version\": \"{}\" }}", env!("CARGO_PKG_VERSION"));
-                return Ok(());
+            if !changed.is_empty() {
+                log::warn!(
+                    "leaving out known good modules that changed since: {}",
+                    changed.join(", ")
+                );
             }
-            _ => {}
+            let unchanged: Vec<_> = known_good
+                .modules
+                .into_iter()
+                .filter(|it| !changed.contains(it))
+                .collect();
+            magic_mount::restrict_modules(&unchanged);
+            true
+        }
+        BootVerdict::Skip(reason) => {
+            log::error!("{reason}");
+            magic_mount::skip_mount(&reason);
+            false
         }
     }
+}
+
+fn main() -> Result<()> {
+    let args: Vec<_> = std::env::args().collect();
+
+    // these have to work on a broken config too
+    if cli::config(&args)? {
+        return Ok(());
+    }
+
+    let config = Config::load()?;
+    // before anything reads or writes the state of this root
+    magic_mount::configure_state_dir(&config.sysroot);
+
+    if cli::run(&config, &args)? {
+        return Ok(());
+    }
 
     init_logger(config.verbose);
 
-    if !utils::ksucalls::check_ksu() {
+    // a separate root does not need ksu, nor its try_umount
+    let native = config.sysroot == Path::new("/");
+    if native && !utils::ksucalls::check_ksu() {
         log::error!("only support KernelSU!!");
         panic!();
     }
@@ -87,6 +126,11 @@ fn main() -> Result<()> {
     log::info!("Magic Mount Starting");
     log::info!("config info:\n{config}");
 
+    // only a real boot can loop
+    if native && !boot_guard(&config) {
+        return Ok(());
+    }
+
     log::debug!(
         "current selinux: {}",
         std::fs::read_to_string("/proc/self/attr/current")?
@@ -112,16 +156,26 @@ fn main() -> Result<()> {
         log::error!("mount tmpfs failed: {e}");
     }
 
+    magic_mount::configure_xattrs(&config.xattrs, config.keep_caps);
     let result = magic_mount::magic_mount(
         &tempdir,
+        &config.sysroot,
         &config.moduledir,
         &config.mountsource,
         &config.partitions,
-        config.umount,
+        &config.priority,
+        native && config.umount,
     );
 
+    // a run that failed outright counts against the module it broke on too
+    if let Err(e) =
+        magic_mount::update_quarantine(&config.moduledir, &result, config.quarantine_after)
+    {
+        log::warn!("failed to update quarantine: {e:#}");
+    }
+
     match result {
-        Ok(()) => {
+        Ok(_) => {
             log::info!("Magic Mount Completed Successfully");
             Ok(())
         }
@@ -7,6 +7,7 @@ use serde::Serialize;
 
 use crate::{
     defs::{DISABLE_FILE_NAME, REMOVE_FILE_NAME, SKIP_MOUNT_FILE_NAME},
+    magic_mount::{self, Conflict, ModuleRules, Quarantine},
     utils::validate_module_id,
 };
 
@@ -19,6 +20,11 @@ pub struct ModuleInfo {
     description: String,
     disabled: bool,
     skip: bool,
+    /// paths this module shares with others
+    conflicts: Vec<Conflict>,
+    rules: ModuleRules,
+    /// why it is no longer mounted, if it broke too many runs
+    quarantine: Option<Quarantine>,
 }
 
 fn read_prop(vaule: &str, key: &str) -> Option<String> {
@@ -37,11 +43,23 @@ fn read_prop(vaule: &str, key: &str) -> Option<String> {
 /// 1. Do not have a `system` directory.
 /// 2. Are disabled or removed.
 /// 3. Have the `skip_mount` flag.
-pub fn scan_modules<P>(module_dir: P, extra: &[String]) -> Vec<ModuleInfo>
+///
+/// Quarantined modules are listed with the reason, they are not mounted.
+pub fn scan_modules<P>(
+    sysroot: &Path,
+    module_dir: P,
+    extra: &[String],
+    priority: &[String],
+) -> Vec<ModuleInfo>
 where
     P: AsRef<Path>,
 {
     let mut modules = Vec::new();
+    let conflicts = magic_mount::conflicts(sysroot, module_dir.as_ref(), extra, priority)
+        .unwrap_or_else(|e| {
+            log::warn!("failed to collect conflicts: {e:#}");
+            Vec::new()
+        });
 
     if let Ok(entries) = module_dir.as_ref().read_dir() {
         for entry in entries.flatten() {
@@ -73,11 +91,20 @@ where
 
             let disabled =
                 path.join(DISABLE_FILE_NAME).exists() || path.join(REMOVE_FILE_NAME).exists();
-            let skip = path.join(SKIP_MOUNT_FILE_NAME).exists();
-            if disabled || skip {
+            if disabled || path.join(SKIP_MOUNT_FILE_NAME).exists() {
                 continue;
             }
 
+            let rules = ModuleRules::load_or_default(&path);
+            let quarantine = magic_mount::load_quarantine(&path);
+            // quarantined, or every partition it ships is skipped by the rules
+            let skip = quarantine.is_some()
+                || partitions
+                    .iter()
+                    .map(|p| path.join(p))
+                    .filter(|p| p.is_dir())
+                    .all(|p| rules.excludes(&p));
+
             let prop_path = path.join("module.prop");
 
             let Ok(prop) = fs::read_to_string(prop_path) else {
@@ -91,6 +118,12 @@ where
                 read_prop(&prop, "description").unwrap_or_else(|| "unknown".to_string());
 
             if validate_module_id(&id).is_ok() {
+                let dir_name = entry.file_name().to_string_lossy().to_string();
+                let conflicts = conflicts
+                    .iter()
+                    .filter(|it| it.modules.contains(&dir_name))
+                    .cloned()
+                    .collect();
                 modules.push(ModuleInfo {
                     id,
                     name,
@@ -99,6 +132,9 @@ where
                     description,
                     disabled,
                     skip,
+                    conflicts,
+                    rules,
+                    quarantine,
                 });
             }
         }
@@ -0,0 +1,347 @@
+// Copyright 2025 Magic Mount-rs Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Runs the real binary against a fake Android root inside a new user and
+//! mount namespace, so the mounts need no privileges and vanish afterwards.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::{fs::symlink, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+/// What `find` saw at one path of the mounted view.
+#[derive(Debug)]
+struct Entry {
+    kind: char,
+    /// file content or symlink target
+    data: String,
+}
+
+/// A fake root and module dir under a scratch dir, removed on drop.
+struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("mmrs-ns-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["root", "modules", "tmp"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        Self { root }
+    }
+
+    fn sysroot(&self) -> PathBuf {
+        self.root.join("root")
+    }
+
+    fn modules(&self) -> PathBuf {
+        self.root.join("modules")
+    }
+
+    fn file<P>(&self, path: P, content: &str)
+    where
+        P: AsRef<Path>,
+    {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn symlink<P>(&self, original: &str, link: P)
+    where
+        P: AsRef<Path>,
+    {
+        let link = self.root.join(link);
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        symlink(original, link).unwrap();
+    }
+
+    fn module(&self, id: &str) {
+        self.file(format!("modules/{id}/module.prop"), &format!("id={id}\n"));
+    }
+
+    /// Mounts the modules in a new namespace after running `prepare` there,
+    /// and returns the resulting view of the fake root. The run keeps its
+    /// state in the fake root too, as it is the sysroot.
+    fn mount(&self, prepare: &str) -> HashMap<String, Entry> {
+        let config = self.root.join("config.toml");
+        fs::write(
+            &config,
+            format!(
+                "moduledir = {:?}\ntmpfsdir = {:?}\nsysroot = {:?}\nverbose = false\numount = false\npartitions = []\n",
+                self.modules(),
+                self.root.join("tmp"),
+                self.sysroot(),
+            ),
+        )
+        .unwrap();
+
+        let script = format!(
+            r#"set -e
+{prepare}
+"$BIN"
+cd "$ROOT"
+# find goes by the dir entry type, which is the placeholder under a mount
+find . -mindepth 1 | while read -r path; do
+    if [ -L "$path" ]; then
+        printf 'l %s %s\n' "$path" "$(readlink "$path")"
+    elif [ -f "$path" ]; then
+        printf 'f %s %s\n' "$path" "$(cat "$path")"
+    elif [ -d "$path" ]; then
+        printf 'd %s\n' "$path"
+    elif [ -p "$path" ]; then
+        printf 'p %s\n' "$path"
+    else
+        printf '? %s\n' "$path"
+    fi
+done
+"#
+        );
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", &script])
+            .env("BIN", env!("CARGO_BIN_EXE_magic_mount_rs"))
+            .env("ROOT", self.sysroot())
+            .env("MODULES", self.modules())
+            .env("MAGIC_MOUNT_CONFIG", &config)
+            .env("RUST_BACKTRACE", "0");
+        enter_namespace(&mut command);
+
+        let output = command.output().expect("failed to spawn sh");
+        assert!(
+            output.status.success(),
+            "mount failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, ' ');
+                let kind = parts.next()?.chars().next()?;
+                let path = parts.next()?.trim_start_matches("./").to_string();
+                let data = parts.next().unwrap_or_default().to_string();
+                Some((path, Entry { kind, data }))
+            })
+            .collect()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Makes `command` start as root of a new user namespace with its own mount
+/// namespace, mapped to the calling user.
+fn enter_namespace(command: &mut Command) {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    // formatted up front, only async signal safe calls after the fork
+    let uid_map = format!("0 {uid} 1");
+    let gid_map = format!("0 {gid} 1");
+
+    unsafe {
+        command.pre_exec(move || {
+            if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            fs::write("/proc/self/setgroups", "deny")?;
+            fs::write("/proc/self/uid_map", &uid_map)?;
+            fs::write("/proc/self/gid_map", &gid_map)?;
+            Ok(())
+        });
+    }
+}
+
+/// An Android like root: vendor and product live at the top with symlinks
+/// from system, like on devices with those partitions split out.
+fn android_root(sandbox: &Sandbox) {
+    sandbox.file("root/system/bin/sh", "stock sh");
+    sandbox.file("root/system/bin/ls", "stock ls");
+    sandbox.file("root/system/etc/hosts", "stock hosts");
+    sandbox.file("root/system/app/Old/Old.apk", "stock apk");
+    sandbox.file("root/vendor/etc/stock.conf", "stock vendor");
+    sandbox.file("root/product/etc/stock.conf", "stock product");
+    sandbox.symlink("../vendor", "root/system/vendor");
+    sandbox.symlink("../product", "root/system/product");
+}
+
+fn kind(view: &HashMap<String, Entry>, path: &str) -> Option<char> {
+    view.get(path).map(|it| it.kind)
+}
+
+fn data<'a>(view: &'a HashMap<String, Entry>, path: &str) -> &'a str {
+    view.get(path)
+        .map_or_else(|| panic!("{path} is missing"), |it| it.data.as_str())
+}
+
+#[test]
+fn new_files_and_symlinks_appear_next_to_stock_ones() {
+    let sandbox = Sandbox::new("new");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/bin/newtool", "a newtool");
+    sandbox.symlink("sh", "modules/mod_a/system/bin/link");
+
+    let view = sandbox.mount("");
+
+    assert_eq!(data(&view, "system/bin/newtool"), "a newtool");
+    assert_eq!(kind(&view, "system/bin/link"), Some('l'));
+    assert_eq!(data(&view, "system/bin/link"), "sh");
+    // the rest of the dir is mirrored into the new tmpfs
+    assert_eq!(data(&view, "system/bin/sh"), "stock sh");
+    assert_eq!(data(&view, "system/bin/ls"), "stock ls");
+    assert_eq!(data(&view, "system/etc/hosts"), "stock hosts");
+}
+
+#[test]
+fn existing_files_are_overridden() {
+    let sandbox = Sandbox::new("override");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/etc/hosts", "a hosts");
+
+    let view = sandbox.mount("");
+
+    assert_eq!(data(&view, "system/etc/hosts"), "a hosts");
+}
+
+#[test]
+fn replaced_dirs_drop_stock_content() {
+    let sandbox = Sandbox::new("replace");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/app/Old/.replace", "");
+    sandbox.file("modules/mod_a/system/app/Old/New.apk", "a apk");
+
+    let view = sandbox.mount("");
+
+    assert_eq!(data(&view, "system/app/Old/New.apk"), "a apk");
+    assert_eq!(kind(&view, "system/app/Old/Old.apk"), None);
+}
+
+#[test]
+fn whiteouts_hide_stock_files() {
+    let sandbox = Sandbox::new("whiteout");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    fs::create_dir_all(sandbox.modules().join("mod_a/system/bin")).unwrap();
+
+    let view = sandbox.mount(r#"mknod "$MODULES/mod_a/system/bin/ls" c 0 0"#);
+
+    assert_eq!(kind(&view, "system/bin/ls"), None);
+    assert_eq!(data(&view, "system/bin/sh"), "stock sh");
+}
+
+#[test]
+fn split_partitions_are_mounted_at_the_top() {
+    let sandbox = Sandbox::new("partitions");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/vendor/etc/new.conf", "a vendor");
+
+    let view = sandbox.mount("");
+
+    assert_eq!(data(&view, "vendor/etc/new.conf"), "a vendor");
+    assert_eq!(data(&view, "vendor/etc/stock.conf"), "stock vendor");
+    assert_eq!(kind(&view, "system/vendor"), Some('l'));
+    assert_eq!(kind(&view, "product/etc/new.conf"), None);
+}
+
+#[test]
+fn disabled_modules_are_left_out() {
+    let sandbox = Sandbox::new("disabled");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/bin/newtool", "a newtool");
+    sandbox.module("mod_b");
+    sandbox.file("modules/mod_b/disable", "");
+    sandbox.file("modules/mod_b/system/bin/ghost", "b ghost");
+
+    let view = sandbox.mount("");
+
+    assert_eq!(data(&view, "system/bin/newtool"), "a newtool");
+    assert_eq!(kind(&view, "system/bin/ghost"), None);
+}
+
+#[test]
+fn fifos_survive_in_modules_and_mirrors() {
+    let sandbox = Sandbox::new("fifo");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/bin/newtool", "a newtool");
+
+    let view =
+        sandbox.mount(r#"mkfifo "$ROOT/system/bin/pipe" "$MODULES/mod_a/system/bin/newpipe""#);
+
+    assert_eq!(kind(&view, "system/bin/newpipe"), Some('p'));
+    assert_eq!(kind(&view, "system/bin/pipe"), Some('p'));
+    assert_eq!(data(&view, "system/bin/newtool"), "a newtool");
+}
+
+#[test]
+fn rules_come_from_the_sysroot() {
+    let sandbox = Sandbox::new("rules");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/bin/newtool", "a newtool");
+    sandbox.file("modules/mod_a/system/etc/hosts", "a hosts");
+    sandbox.file(
+        "root/data/adb/magic_mount/rules/mod_a.toml",
+        "[paths]\n\"/system/etc\" = \"skip\"\n",
+    );
+
+    let view = sandbox.mount("");
+
+    assert_eq!(data(&view, "system/bin/newtool"), "a newtool");
+    assert_eq!(data(&view, "system/etc/hosts"), "stock hosts");
+}
+
+/// Every file in `dir` with its size and mtime, empty if it does not exist.
+fn snapshot(dir: &Path) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn state_stays_in_the_sandbox() {
+    // the device's, which a run against a sysroot must leave alone
+    let host = Path::new("/data/adb/magic_mount");
+    let before = snapshot(host);
+
+    let sandbox = Sandbox::new("state");
+    android_root(&sandbox);
+    sandbox.module("mod_a");
+    sandbox.file("modules/mod_a/system/bin/newtool", "a newtool");
+
+    let view = sandbox.mount("");
+
+    for name in ["state.json", "report.json", "tree", "strikes.json"] {
+        assert_eq!(
+            kind(&view, &format!("data/adb/magic_mount/{name}")),
+            Some('f'),
+            "{name} is missing"
+        );
+    }
+    let state = sandbox.sysroot().join("data/adb/magic_mount/state.json");
+    assert!(fs::read_to_string(state).unwrap().contains("mod_a"));
+    assert_eq!(snapshot(host), before);
+}
@@ -8,6 +8,7 @@ import type {
   MagicConfig,
   MagicModule,
   StorageUsage,
+  SyncStep,
   SystemInfo,
 } from "./api";
 import { DEFAULT_CONFIG } from "./constants";
@@ -132,6 +133,20 @@ export const MockAPI = {
     return "1.2.0-mock";
   },
 
+  syncMounts: async (): Promise<SyncStep[]> => {
+    await delay(MOCK_DELAY);
+    console.log("[MockAPI] syncMounts");
+
+    return [
+      {
+        path: "/system/app",
+        action: "remount",
+        modules: ["youtube-revanced"],
+        error: null,
+      },
+    ];
+  },
+
   reboot: async (): Promise<void> => {
     console.log("[MockAPI] Reboot requested");
     // eslint-disable-next-line no-alert
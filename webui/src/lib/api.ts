@@ -45,6 +45,13 @@ export interface StorageUsage {
   hymofs_available: boolean;
 }
 
+export interface SyncStep {
+  path: string;
+  action: "mount" | "remount" | "unmount";
+  modules: string[];
+  error: string | null;
+}
+
 export interface DeviceStatus {
   model: string;
   android: string;
@@ -127,6 +134,8 @@ function parseKvConfig(text: string): MagicConfig {
           result.moduledir = value;
           break;
         }
+        // tempdir is the old name, written by earlier versions
+        case "tmpfsdir":
         case "tempdir": {
           result.tempdir = value;
           break;
@@ -154,21 +163,28 @@ function parseKvConfig(text: string): MagicConfig {
   }
 }
 
-function serializeKvConfig(cfg: MagicConfig): string {
-  const q = (s: string) => `"${s}"`;
-  const lines = ["# Magic Mount Configuration File", ""];
+/** Quotes `s` as a single shell word. */
+function shellQuote(s: string): string {
+  return `'${s.replaceAll("'", `'\\''`)}'`;
+}
 
-  lines.push(`moduledir = ${q(cfg.moduledir)}`);
+/** `meta-mm config set` arguments for `cfg`, every value as TOML. */
+function configSetArgs(cfg: MagicConfig): string {
+  const values: Record<string, unknown> = {
+    moduledir: cfg.moduledir,
+    mountsource: cfg.mountsource,
+    verbose: cfg.verbose,
+    umount: !cfg.disable_umount,
+    partitions: cfg.partitions,
+  };
   if (cfg.tempdir) {
-    lines.push(`tempdir = ${q(cfg.tempdir)}`);
+    values.tmpfsdir = cfg.tempdir;
   }
-  lines.push(`mountsource = ${q(cfg.mountsource)}`);
-  lines.push(`verbose = ${cfg.verbose}`);
-  lines.push(`umount = ${!cfg.disable_umount}`);
-  const parts = cfg.partitions.map((p) => q(p)).join(", ");
-  lines.push(`partitions = [${parts}]`);
 
-  return lines.join("\n");
+  // JSON strings, booleans and string arrays are TOML as well
+  return Object.entries(values)
+    .map(([key, value]) => `${key} ${shellQuote(JSON.stringify(value))}`)
+    .join(" ");
 }
 
 function formatBytes(bytes: number, decimals = 2): string {
@@ -183,8 +199,44 @@ function formatBytes(bytes: number, decimals = 2): string {
   return `${Number.parseFloat((bytes / k ** i).toFixed(dm))} ${sizes[i]}`;
 }
 
+/** Modules the last mount run actually mounted, null if it is unknown. */
+async function loadMountedModules(): Promise<string[] | null> {
+  const cmd = "/data/adb/modules/magic_mount_rs/meta-mm status --json";
+  try {
+    const { errno, stdout } = await ksuExec!(cmd);
+    if (errno === 0 && stdout) {
+      const state = JSON.parse(stdout);
+
+      // a state from a previous boot means nothing was mounted in this one
+      return state.stale ? [] : (state.modules ?? []);
+    }
+  } catch (e) {
+    console.error("Mount status error:", e);
+  }
+
+  return null;
+}
+
 const RealAPI = {
   loadConfig: async (): Promise<MagicConfig> => {
+    try {
+      const { errno, stdout } = await ksuExec!(
+        "/data/adb/modules/magic_mount_rs/meta-mm config dump --json",
+      );
+      if (errno === 0 && stdout) {
+        const raw = JSON.parse(stdout);
+
+        return {
+          ...raw,
+          tempdir: raw.tmpfsdir ?? "",
+          disable_umount: !raw.umount,
+        };
+      }
+    } catch (e) {
+      console.error("Config dump error:", e);
+    }
+
+    // a config meta-mm cannot load, show what can be read of it
     try {
       const { errno, stdout } = await ksuExec!(
         `[ -f "${PATHS.CONFIG}" ] && cat "${PATHS.CONFIG}" || echo ""`,
@@ -205,14 +257,12 @@ const RealAPI = {
   },
 
   saveConfig: async (config: MagicConfig): Promise<void> => {
-    const content = serializeKvConfig(config);
-    const cmd = `
-      mkdir -p "$(dirname "${PATHS.CONFIG}")"
-      cat > "${PATHS.CONFIG}" << 'EOF_CONFIG'
-${content}
-EOF_CONFIG
-      chmod 644 "${PATHS.CONFIG}"
-    `;
+    // meta-mm keeps comments and other keys, and checks the values first
+    const bin = "/data/adb/modules/magic_mount_rs/meta-mm";
+    let cmd = `${bin} config set ${configSetArgs(config)}`;
+    if (!config.tempdir) {
+      cmd += ` && ${bin} config unset tmpfsdir`;
+    }
     const { errno, stderr } = await ksuExec!(cmd);
     if (errno !== 0) {
       throw new Error(`Failed to save config: ${stderr}`);
@@ -226,6 +276,7 @@ EOF_CONFIG
       if (errno === 0 && stdout) {
         try {
           const rawModules = JSON.parse(stdout);
+          const mounted = await loadMountedModules();
 
           return rawModules.map((m: any) => ({
             id: m.id,
@@ -233,9 +284,9 @@ EOF_CONFIG
             version: m.version,
             author: m.author ?? "Unknown",
             description: m.description,
-            is_mounted: !m.skip,
-            mode: "magic",
-            rules: { default_mode: "magic", paths: {} },
+            is_mounted: mounted ? mounted.includes(m.id) : !m.skip,
+            mode: m.rules?.default_mode ?? "magic",
+            rules: m.rules ?? { default_mode: "magic", paths: {} },
           }));
         } catch (parseError) {
           console.error("Failed to parse module JSON:", parseError);
@@ -353,6 +404,21 @@ EOF_CONFIG
     return "Unknown";
   },
 
+  syncMounts: async (): Promise<SyncStep[]> => {
+    const cmd = "/data/adb/modules/magic_mount_rs/meta-mm sync --json";
+    const { errno, stdout, stderr } = await ksuExec!(cmd);
+    // one step per line, failed steps are reported before the non zero exit
+    const steps: SyncStep[] = stdout
+      .split("\n")
+      .filter((line) => line.trim())
+      .map((line) => JSON.parse(line));
+    if (errno !== 0 && steps.length === 0) {
+      throw new Error(`Failed to sync mounts: ${stderr}`);
+    }
+
+    return steps;
+  },
+
   openLink: async (url: string) => {
     const safeUrl = url.replace(/"/g, '\\"');
     const cmd = `am start -a android.intent.action.VIEW -d "${safeUrl}"`;
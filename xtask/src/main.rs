@@ -170,6 +170,7 @@ fn build() -> Result<()> {
             "metamount.sh",
             "loader",
             "uninstall.sh",
+            "boot-completed.sh",
         ])?;
     } else {
         let _ = fs::remove_file(temp_dir.join("daemonize-mmrs"));